@@ -42,7 +42,7 @@ pub fn list_avatars(custom_avatars_path: &Path) -> IndexMap<Ulid, PathBuf> {
 }
 
 pub fn update_and_native_notify_avatars(context: &AppContext) {
-    let avatars_path = custom_avatars_path(context);
+    let avatars_path = custom_avatars_path(&context.state.data_dir);
     let avatars = list_avatars(&avatars_path);
 
     let avatars_as_ulids = avatars.keys().map(|u| u.to_string()).collect();
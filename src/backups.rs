@@ -0,0 +1,187 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::state::AppState;
+use crate::storage::{backups_path, bump_state_revision, write_file_atomic};
+
+// === PROFILES.INI BACKUPS ===
+
+// Number of backups to keep, older backups are deleted
+const MAX_BACKUPS: usize = 10;
+
+const PROFILES_INI_FILENAME: &str = "profiles.ini";
+const INSTALLS_INI_FILENAME: &str = "installs.ini";
+
+/// List the IDs of all backups, newest first
+pub fn list_backups(data_dir: &Path) -> Vec<String> {
+    let mut backups: Vec<String> = match fs::read_dir(backups_path(data_dir)) {
+        Ok(r) => r.filter_map(|e| e.ok())
+            .filter(|e| e.path().join(PROFILES_INI_FILENAME).is_file())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    // Backup IDs are timestamps so sorting them sorts them by age
+    backups.sort_unstable_by(|a, b| b.cmp(a));
+    backups
+}
+
+/// Copy the current profiles.ini and installs.ini into a new backup, deleting the oldest backups.
+/// Nothing is copied if they did not change since the last backup. Will log if the backup fails.
+pub fn backup_profiles_ini(app_state: &AppState) {
+    if let Err(e) = try_backup_profiles_ini(app_state) {
+        log::error!("Failed to backup profiles.ini: {:?}", e);
+    }
+}
+
+fn try_backup_profiles_ini(app_state: &AppState) -> io::Result<()> {
+    let profiles_ini = match fs::read(app_state.config.profiles_ini_path()) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    let installs_ini = fs::read(app_state.config.installs_ini_path()).ok();
+
+    let backups_dir = backups_path(&app_state.data_dir);
+    let backups = list_backups(&app_state.data_dir);
+
+    if let Some(latest) = backups.first() {
+        let latest_dir = backups_dir.join(latest);
+        let unchanged = fs::read(latest_dir.join(PROFILES_INI_FILENAME)).ok().as_ref() == Some(&profiles_ini)
+            && fs::read(latest_dir.join(INSTALLS_INI_FILENAME)).ok() == installs_ini;
+        if unchanged {
+            return Ok(())
+        }
+    }
+
+    let (backup_id, backup_dir) = create_backup_dir(&backups_dir)?;
+    if let Some(installs_ini) = installs_ini {
+        fs::write(backup_dir.join(INSTALLS_INI_FILENAME), installs_ini)?;
+    }
+    // Write profiles.ini last as it's presence marks the backup as complete
+    fs::write(backup_dir.join(PROFILES_INI_FILENAME), profiles_ini)?;
+    log::trace!("Created profiles.ini backup: {}", backup_id);
+
+    // Delete old backups, the new backup is not in the list yet
+    for old_backup in backups.iter().skip(MAX_BACKUPS - 1) {
+        if let Err(e) = fs::remove_dir_all(backups_dir.join(old_backup)) {
+            log::warn!("Failed to delete old backup {}: {:?}", old_backup, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Create the directory of a new backup. Backups created within the same millisecond get a
+/// counter after the timestamp so they still sort by age.
+fn create_backup_dir(backups_dir: &Path) -> io::Result<(String, PathBuf)> {
+    fs::create_dir_all(backups_dir)?;
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    for counter in 0..1000 {
+        let backup_id = if counter == 0 {
+            timestamp.clone()
+        } else {
+            format!("{}-{:03}", timestamp, counter)
+        };
+        let backup_dir = backups_dir.join(&backup_id);
+        match fs::create_dir(&backup_dir) {
+            Ok(()) => return Ok((backup_id, backup_dir)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "Too many backups with the same timestamp"))
+}
+
+#[derive(Debug)]
+pub enum RestoreBackupError {
+    BackupNotFound,
+    ReadBackupError(io::Error),
+    WriteIniError(io::Error)
+}
+
+/// Overwrite profiles.ini and installs.ini with the contents of a backup. The current files are
+/// backed up first so the restore can be undone.
+pub fn restore_backup(app_state: &AppState, backup_id: &str) -> Result<(), RestoreBackupError> {
+    // Only accept IDs that we listed to avoid restoring arbitrary paths
+    if !list_backups(&app_state.data_dir).iter().any(|b| b == backup_id) {
+        return Err(RestoreBackupError::BackupNotFound)
+    }
+    let backup_dir = backups_path(&app_state.data_dir).join(backup_id);
+
+    let profiles_ini = fs::read(backup_dir.join(PROFILES_INI_FILENAME))
+        .map_err(RestoreBackupError::ReadBackupError)?;
+    let installs_ini = match fs::read(backup_dir.join(INSTALLS_INI_FILENAME)) {
+        Ok(c) => Some(c),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(RestoreBackupError::ReadBackupError(e))
+    };
+
+    backup_profiles_ini(app_state);
+
+    write_file_atomic(&app_state.config.profiles_ini_path(), &profiles_ini)
+        .map_err(RestoreBackupError::WriteIniError)?;
+    if let Some(installs_ini) = installs_ini {
+        write_file_atomic(&app_state.config.installs_ini_path(), &installs_ini)
+            .map_err(RestoreBackupError::WriteIniError)?;
+    }
+
+    bump_state_revision(&app_state.data_dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{test_app_state, TempDir};
+    use super::*;
+
+    #[test]
+    fn backups_are_rotated() {
+        let root = TempDir::new("backups-test");
+        let state = test_app_state(&root);
+
+        // Backups made in quick succession must not overwrite each other
+        let backup_count = MAX_BACKUPS + 2;
+        for i in 0..backup_count {
+            fs::write(state.config.profiles_ini_path(), format!("[Profile{}]", i)).unwrap();
+            try_backup_profiles_ini(&state).unwrap();
+        }
+
+        let backups = list_backups(&state.data_dir);
+        assert_eq!(backups.len(), MAX_BACKUPS);
+        for (backup, i) in backups.iter().zip((0..backup_count).rev()) {
+            let contents = fs::read_to_string(backups_path(&state.data_dir).join(backup).join(PROFILES_INI_FILENAME)).unwrap();
+            assert_eq!(contents, format!("[Profile{}]", i));
+        }
+    }
+
+    #[test]
+    fn unchanged_profiles_ini_is_not_backed_up_again() {
+        let root = TempDir::new("backups-test");
+        let state = test_app_state(&root);
+
+        try_backup_profiles_ini(&state).unwrap();
+        try_backup_profiles_ini(&state).unwrap();
+        assert_eq!(list_backups(&state.data_dir).len(), 1);
+    }
+
+    #[test]
+    fn restore_backup_only_accepts_listed_ids() {
+        let root = TempDir::new("backups-test");
+        let state = test_app_state(&root);
+        let original = fs::read(state.config.profiles_ini_path()).unwrap();
+        try_backup_profiles_ini(&state).unwrap();
+        let backup_id = list_backups(&state.data_dir).remove(0);
+        fs::write(state.config.profiles_ini_path(), "[Profile0]").unwrap();
+
+        for backup_id in ["", ".", "..", "../data", "unknown"] {
+            assert!(matches!(restore_backup(&state, backup_id), Err(RestoreBackupError::BackupNotFound)), "{:?} was accepted", backup_id);
+        }
+        assert_eq!(fs::read_to_string(state.config.profiles_ini_path()).unwrap(), "[Profile0]");
+
+        restore_backup(&state, &backup_id).unwrap();
+        assert_eq!(fs::read(state.config.profiles_ini_path()).unwrap(), original);
+        // The replaced profiles.ini was backed up as well
+        assert_eq!(list_backups(&state.data_dir).len(), 2);
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::Path;
+use crate::config::read_configuration;
+use crate::ephemeral::supervise_ephemeral_profile;
+use crate::locking::StoreLock;
+use crate::cmd::create_profile::{check_name_conflict, register_new_profile};
+use crate::ipc::notify_profile_changed_detached;
+use crate::native_resp::NativeResponse;
+use crate::profile_archive::{discard_imported_profile, export_profile, import_profile, read_archive_manifest};
+use crate::profiles::{read_profiles, ProfileEntry, ProfilesIniState};
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
+use crate::state::AppState;
+use crate::storage::custom_avatars_path;
+
+// === COMMAND LINE ===
+
+/// Started by the connector to clean up an ephemeral profile, not meant to be run by the user
+pub const SUPERVISE_EPHEMERAL_COMMAND: &str = "supervise-ephemeral";
+
+const USAGE: &str = "Usage:
+  firefox_profile_switcher_connector export <profile name or id> <archive>
+  firefox_profile_switcher_connector import <archive> [profile name]";
+
+/// Run the command specified in the arguments, returns the exit code or `None` if the arguments
+/// are not a command
+pub fn try_run_cli(args: &[String]) -> Option<i32> {
+    // The browser always passes the path to our manifest as the first argument, so we only treat
+    // the arguments as a command if the first one is the name of one of these commands
+    let result = match args.get(1).map(String::as_str) {
+        Some("export") => match (args.get(2), args.get(3)) {
+            (Some(profile), Some(archive)) => cli_export(&build_app_state(), profile, Path::new(archive)),
+            _ => Err(USAGE.to_owned())
+        },
+        Some("import") => match args.get(2) {
+            Some(archive) => cli_import(&build_app_state(), Path::new(archive), args.get(3).map(String::as_str)),
+            None => Err(USAGE.to_owned())
+        },
+        Some(SUPERVISE_EPHEMERAL_COMMAND) => match args.get(2) {
+            Some(profile_id) => supervise_ephemeral_profile(&build_app_state(), profile_id)
+                .map_err(|e| format!("Failed to clean up ephemeral profile: {}", e)),
+            None => Err(USAGE.to_owned())
+        },
+        Some("help") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => return None
+    };
+
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    })
+}
+
+fn build_app_state() -> AppState {
+    let project_dirs = crate::project_dirs();
+    let pref_dir = project_dirs.preference_dir();
+    let data_dir = project_dirs.data_local_dir();
+
+    AppState {
+        config: read_configuration(&pref_dir.join("config.json")),
+        first_run: false,
+        cur_profile_id: None,
+        extension_id: None,
+        extension_version: None,
+        internal_extension_id: None,
+        config_dir: pref_dir.to_path_buf(),
+        data_dir: data_dir.to_path_buf(),
+    }
+}
+
+fn cli_export(app_state: &AppState, profile_ref: &str, archive: &Path) -> Result<(), String> {
+    let profiles = read_profiles(&app_state.config, &app_state.config_dir)
+        .map_err(|e| format!("Failed to load profile list: {:?}", e))?;
+    let profile = profiles.profile_entries.iter()
+        .find(|p| p.id == profile_ref || p.name.trim().eq_ignore_ascii_case(profile_ref.trim()))
+        .ok_or_else(|| format!("No profile named {:?} could be found.", profile_ref))?;
+
+    let profile_path = profile.full_path(&app_state.config);
+    match detect_profile_lock_state(&profile_path, &app_state.config) {
+        ProfileLockState::Closed => {}
+        ProfileLockState::Running => return Err("This profile is in use and therefore cannot be exported, close the profile and try again.".to_owned()),
+        ProfileLockState::Stale => return Err("This profile was not shut down properly, open and close the profile and try again.".to_owned())
+    }
+
+    export_profile(profile, &profile_path, &custom_avatars_path(&app_state.data_dir), archive)
+        .map_err(|e| format!("Failed to export profile: {}", e))?;
+
+    println!("Exported profile {:?} to {}", profile.name, archive.display());
+    Ok(())
+}
+
+fn cli_import(app_state: &AppState, archive: &Path, name: Option<&str>) -> Result<(), String> {
+    // Do not unpack an archive whose profile cannot be registered anyway
+    let name = match name {
+        Some(name) => name.to_owned(),
+        None => read_archive_manifest(archive)
+            .map_err(|e| format!("Failed to import profile: {}", e))?
+            .name
+    };
+    let name = name.trim().to_owned();
+    {
+        let (_store_lock, profiles) = lock_profiles(app_state)?;
+        check_name_conflict(&profiles, &name).map_err(native_error_message)?;
+    }
+
+    let mut new_profile = ProfileEntry::new_relative(&name, None, Default::default());
+    let new_profile_full_path = new_profile.full_path(&app_state.config);
+
+    let imported = import_profile(archive, &new_profile_full_path, &custom_avatars_path(&app_state.data_dir))
+        .map_err(|e| format!("Failed to import profile: {}", e))?;
+    new_profile.avatar = imported.avatar.clone();
+    new_profile.options = imported.options.clone();
+
+    let result = lock_profiles(app_state).and_then(|(_store_lock, mut profiles)| {
+        check_name_conflict(&profiles, &name).map_err(native_error_message)?;
+        register_new_profile(app_state, &mut profiles, new_profile).map_err(native_error_message)?;
+        notify_profile_changed_detached(&profiles);
+        Ok(())
+    });
+    if result.is_err() {
+        discard_imported_profile(&imported, &new_profile_full_path);
+    }
+    result?;
+
+    println!("Imported profile {:?} into {}", name, new_profile_full_path.display());
+    Ok(())
+}
+
+/// Take the store lock and read the profile list, see `lock_stores`
+fn lock_profiles(app_state: &AppState) -> Result<(StoreLock, ProfilesIniState), String> {
+    fs::create_dir_all(&app_state.data_dir)
+        .map_err(|e| format!("Failed to create data folder: {:?}", e))?;
+    let store_lock = StoreLock::acquire(&app_state.data_dir)
+        .map_err(|e| format!("Profiles are being modified by another window, please try again: {}", e))?;
+    let profiles = read_profiles(&app_state.config, &app_state.config_dir)
+        .map_err(|e| format!("Failed to load profile list: {:?}", e))?;
+    Ok((store_lock, profiles))
+}
+
+/// The message of an error response of a command that is reused by the CLI
+fn native_error_message(resp: NativeResponse) -> String {
+    match resp {
+        NativeResponse::Error { error, debug_msg: Some(debug_msg), .. } => format!("{} ({})", error, debug_msg),
+        NativeResponse::Error { error, .. } => error,
+        _ => "Unexpected response.".to_owned()
+    }
+}
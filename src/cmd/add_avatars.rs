@@ -3,9 +3,12 @@ use ulid::Ulid;
 use crate::{AppContext, NativeResponse};
 use crate::avatars::build_avatar_path;
 use crate::ipc::notify_update_avatars;
+use crate::native_resp::NativeErrorCode;
 use crate::native_resp::NativeResponseData::AvatarsUpdated;
 use crate::profiles::ProfilesIniState;
-use crate::storage::{custom_avatars_path};
+use crate::storage::{bump_state_revision, custom_avatars_path};
+use crate::locking::StoreLock;
+use crate::cmd::store_busy_error;
 
 pub fn process_cmd_add_avatars(context: &AppContext, profiles: ProfilesIniState) -> NativeResponse {
     // Pick avatar
@@ -15,29 +18,33 @@ pub fn process_cmd_add_avatars(context: &AppContext, profiles: ProfilesIniState)
     };
 
     // Load and create avatars dir
-    let avatars_dir = custom_avatars_path(context);
+    let avatars_dir = custom_avatars_path(&context.state.data_dir);
     if let Err(e) = fs::create_dir_all(&avatars_dir) {
-        return NativeResponse::error_with_dbg_msg("Could not create folder for avatars.", e);
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::AvatarStoreFailed, "Could not create folder for avatars.", e);
     }
 
     // Verify avatars are the correct size
     for path in &result {
         let metadata = match path.metadata() {
             Ok(m) => m,
-            Err(e) => return NativeResponse::error_with_dbg_msg(format!("Could not load information on avatar: {}", path.display()), e)
+            Err(e) => return NativeResponse::error_with_dbg_msg(NativeErrorCode::InvalidAvatar, format!("Could not load information on avatar: {}", path.display()), e)
         };
 
         // 500 KB
         if metadata.len() > 500000 {
-            return NativeResponse::error(format!("Avatar {} is too large, max size is 500 KB", path.display()));
+            return NativeResponse::error(NativeErrorCode::AvatarTooLarge, format!("Avatar {} is too large, max size is 500 KB", path.display()));
         }
     }
 
-    // Save all the avatars
+    // Save all the avatars, the picker is not covered by the lock as the user may take a while
+    let _store_lock = match StoreLock::acquire(&context.state.data_dir) {
+        Ok(l) => l,
+        Err(e) => return store_busy_error(e)
+    };
     for path in result {
         let extension = match path.extension().and_then(|x| x.to_str()) {
             Some(e) => e,
-            None => return NativeResponse::error(&format!("Invalid avatar: {}", path.display()))
+            None => return NativeResponse::error(NativeErrorCode::InvalidAvatar, &format!("Invalid avatar: {}", path.display()))
         };
         let target_path = build_avatar_path(
             &avatars_dir,
@@ -45,8 +52,9 @@ pub fn process_cmd_add_avatars(context: &AppContext, profiles: ProfilesIniState)
             &extension.to_lowercase(),
         );
         if let Err(e) = fs::copy(&path, target_path) {
-            return NativeResponse::error(&format!("Failed to save avatar: {}. Error: {:?}", path.display(), e))
+            return NativeResponse::error(NativeErrorCode::AvatarStoreFailed, &format!("Failed to save avatar: {}. Error: {:?}", path.display(), e))
         }
+        bump_state_revision(&context.state.data_dir);
     }
 
     notify_update_avatars(context, &profiles);
@@ -0,0 +1,34 @@
+use crate::AppContext;
+use crate::backups::{list_backups, restore_backup, RestoreBackupError};
+use crate::ipc::notify_profile_changed;
+use crate::native_req::NativeMessageRestoreBackup;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::profiles::read_profiles;
+use crate::profiles_order::OrderData;
+
+pub fn process_cmd_list_backups(context: &AppContext) -> NativeResponse {
+    NativeResponse::success(NativeResponseData::Backups {
+        backups: list_backups(&context.state.data_dir)
+    })
+}
+
+pub fn process_cmd_restore_backup(context: &AppContext, msg: NativeMessageRestoreBackup) -> NativeResponse {
+    if let Err(e) = restore_backup(context.state, &msg.backup_id) {
+        return match e {
+            RestoreBackupError::BackupNotFound => NativeResponse::error(NativeErrorCode::BackupNotFound, "No backup with the specified id could be found!"),
+            RestoreBackupError::ReadBackupError(err) => NativeResponse::error_with_dbg_msg(NativeErrorCode::StoreReadFailed, "Failed to read backup.", err),
+            RestoreBackupError::WriteIniError(err) => NativeResponse::error_with_dbg_msg(NativeErrorCode::StoreWriteFailed, "Failed to restore backup.", err),
+        }
+    }
+
+    // The restored profile list may contain different profiles
+    match read_profiles(&context.state.config, &context.state.config_dir) {
+        Ok(profiles) => {
+            OrderData::try_rewrite(context, &profiles);
+            notify_profile_changed(context, &profiles);
+        }
+        Err(e) => return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Backup was restored but the restored profile list could not be loaded.", e)
+    }
+
+    NativeResponse::success(NativeResponseData::BackupRestored)
+}
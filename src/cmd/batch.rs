@@ -0,0 +1,113 @@
+use crate::AppContext;
+use crate::state::AppState;
+use crate::profiles::{ProfilesIniState, write_profiles};
+use crate::native_req::{NativeMessageBatch, NativeMessageBatchStep};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::ipc::{notify_profile_changed, notify_update_profile_order};
+use crate::cmd::update_profile::apply_update_profile;
+use crate::cmd::update_profiles_order::build_profile_order;
+use crate::profiles_order::OrderData;
+use crate::storage::{avatar_data_path, options_data_path, order_data_path, FileSnapshot};
+
+pub fn process_cmd_batch(context: &AppContext, mut profiles: ProfilesIniState, msg: NativeMessageBatch) -> NativeResponse {
+    let mut results = Vec::with_capacity(msg.steps.len());
+    let mut profiles_changed = false;
+    let mut new_order_data = None;
+
+    // Apply every step in memory first, nothing is written if any of them fail
+    for (i, step) in msg.steps.into_iter().enumerate() {
+        let result = match step {
+            NativeMessageBatchStep::UpdateProfile(msg) => apply_update_profile(&mut profiles, msg)
+                .map(|profile| {
+                    profiles_changed = true;
+                    NativeResponseData::ProfileUpdated { profile }
+                }),
+            NativeMessageBatchStep::UpdateProfileOrder(msg) => build_profile_order(&context.state.config_dir, &profiles, msg)
+                .map(|order_data| {
+                    new_order_data = Some(order_data);
+                    NativeResponseData::ProfileOrderUpdated
+                })
+        };
+        match result {
+            Ok(r) => results.push(r),
+            Err(e) => {
+                log::info!("Batch step {} failed, discarding all changes: {:?}", i, e);
+                return e;
+            }
+        }
+    }
+
+    if let Err(e) = write_batch(context.state, profiles_changed.then_some(&profiles), new_order_data.as_ref()) {
+        return e;
+    }
+
+    // Notify other instances only once for the whole batch
+    if profiles_changed {
+        notify_profile_changed(context, &profiles);
+    }
+    if new_order_data.is_some() {
+        notify_update_profile_order(context, &profiles);
+    }
+
+    NativeResponse::success(NativeResponseData::BatchApplied { results })
+}
+
+// The stores are written one after another, put them all back if any of them cannot be written
+fn write_batch(state: &AppState, profiles: Option<&ProfilesIniState>, order_data: Option<&OrderData>) -> Result<(), NativeResponse> {
+    let snapshot = FileSnapshot::take(vec![
+        order_data_path(&state.config_dir),
+        avatar_data_path(&state.config_dir),
+        options_data_path(&state.config_dir),
+        state.config.profiles_ini_path(),
+        state.config.installs_ini_path()
+    ]).map_err(|e| NativeResponse::error_with_dbg_msg(NativeErrorCode::StoreWriteFailed, "Failed to save new changes!", e))?;
+    if let Some(order_data) = order_data {
+        if let Err(e) = order_data.write(state) {
+            snapshot.restore();
+            return Err(NativeResponse::error_with_dbg_msg(NativeErrorCode::StoreWriteFailed, "Could not save profile order.", e));
+        }
+    }
+    if let Some(profiles) = profiles {
+        if let Err(e) = write_profiles(state, profiles) {
+            snapshot.restore();
+            return Err(NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::profiles::read_profiles;
+    use crate::test_util::{app_state_for, test_app_state, TempDir};
+    use super::*;
+
+    #[test]
+    fn failed_write_restores_every_store() {
+        let root = TempDir::new("batch-test");
+        let state = test_app_state(&root);
+        let mut profiles = read_profiles(&state.config, &state.config_dir).unwrap();
+        write_profiles(&state, &profiles).unwrap();
+        let order_data = OrderData { order: vec![profiles.profile_entries[0].id.clone()], revision: 1 };
+        order_data.write(&state).unwrap();
+
+        let stores = [
+            order_data_path(&state.config_dir),
+            avatar_data_path(&state.config_dir),
+            options_data_path(&state.config_dir)
+        ];
+        let before: Vec<Vec<u8>> = stores.iter().map(|p| fs::read(p).unwrap()).collect();
+
+        // The order is written before profiles.ini, which cannot be written as its folder is gone
+        profiles.profile_entries[0].avatar = Some("custom:avatar".to_owned());
+        profiles.profile_entries[0].options.insert("theme".to_owned(), "dark".into());
+        let new_order_data = OrderData { order: Vec::new(), revision: 2 };
+        let broken_state = app_state_for(&root, &root.join("missing"));
+        assert!(write_batch(&broken_state, Some(&profiles), Some(&new_order_data)).is_err());
+
+        let after: Vec<Vec<u8>> = stores.iter().map(|p| fs::read(p).unwrap()).collect();
+        assert_eq!(after, before);
+        assert!(!broken_state.config.profiles_ini_path().exists());
+    }
+}
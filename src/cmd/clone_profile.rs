@@ -0,0 +1,59 @@
+use std::fs;
+use crate::AppContext;
+use crate::cmd::{check_profile_closed, lock_stores};
+use crate::cmd::create_profile::{check_name_conflict, notify_new_profile, register_new_profile};
+use crate::native_req::NativeMessageCloneProfile;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::profile_files::{copy_dir_filtered, is_portable_profile_file};
+use crate::profiles::{ProfileEntry, ProfilesIniState};
+
+pub fn process_cmd_clone_profile(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageCloneProfile) -> NativeResponse {
+    let source = match profiles.profile_entries.iter().find(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+
+    if let Err(e) = check_name_conflict(&profiles, &msg.name) {
+        return e;
+    }
+
+    let source_path = source.full_path(&context.state.config);
+
+    if let Err(e) = check_profile_closed(&source_path, &context.state.config, "copied") {
+        return e;
+    }
+
+    let new_profile = ProfileEntry::new_relative(
+        &msg.name,
+        msg.avatar.or_else(|| source.avatar.clone()),
+        msg.options.unwrap_or_else(|| source.options.clone())
+    );
+
+    let new_profile_full_path = new_profile.full_path(&context.state.config);
+    log::trace!("Copying profile {:?} to {:?}", source_path, new_profile_full_path);
+    if let Err(e) = copy_dir_filtered(&source_path, &new_profile_full_path, &is_portable_profile_file) {
+        if let Err(e) = fs::remove_dir_all(&new_profile_full_path) {
+            log::error!("Failed to clean up partially copied profile: {:?}", e);
+        }
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileCopyFailed, "Failed to copy profile!", e);
+    }
+
+    // The copy can take a while so the stores are only locked to register it
+    let result = lock_stores(context.state).and_then(|(_store_lock, mut profiles)| {
+        check_name_conflict(&profiles, &new_profile.name)?;
+        let resp = register_new_profile(context.state, &mut profiles, new_profile)?;
+        notify_new_profile(context, &profiles);
+        Ok(resp)
+    });
+
+    match result {
+        Ok(resp) => NativeResponse::success(NativeResponseData::ProfileCloned { profile: resp }),
+        Err(e) => {
+            // Do not leave an unregistered copy behind in the browser profile dir
+            if let Err(e) = fs::remove_dir_all(&new_profile_full_path) {
+                log::error!("Failed to clean up copied profile: {:?}", e);
+            }
+            e
+        }
+    }
+}
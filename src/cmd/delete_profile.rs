@@ -1,52 +1,69 @@
-use crate::profiles::{check_profile_active, ProfilesIniState, write_profiles};
+use crate::profiles::{ProfilesIniState, write_profiles};
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
 use crate::native_req::NativeMessageDeleteProfile;
-use crate::native_resp::{NativeResponse, NativeResponseData};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
 use crate::ipc::notify_profile_changed;
-use std::fs;
+use crate::trash::{move_to_trash, take_from_trash};
 use crate::AppContext;
 use crate::profiles_order::OrderData;
+use crate::cmd::check_expected_revision;
 
 pub fn process_cmd_delete_profile(context: &AppContext, mut profiles: ProfilesIniState, msg: NativeMessageDeleteProfile) -> NativeResponse {
     let profile_index = match profiles.profile_entries.iter().position(|p| p.id == msg.profile_id) {
         Some(p) => p,
-        None => return NativeResponse::error("No profile with the specified id could be found!")
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
     };
 
+    if let Err(e) = check_expected_revision(msg.expected_revision, profiles.profile_entries[profile_index].revision) {
+        return e;
+    }
+
     // Delete profile from profile list (but do not write new list yet)
     let profile = profiles.profile_entries.remove(profile_index);
 
     let profile_path = profile.full_path(&context.state.config);
 
-    // Check that profile is closed
-    if check_profile_active(&profile_path) {
-        return NativeResponse::error(
-            concat!(
-            "This profile is in use and therefore cannot be deleted, close the profile and try again.\n\n",
-            "Alternatively, your browser may have crashed the last time you used this profile and the profile was never properly shut down, ",
-            "you can try opening and closing the profile to resolve this issue."
-            )
-        )
+    // Check that profile is closed, profiles left behind by a crashed browser can still be deleted
+    match detect_profile_lock_state(&profile_path, &context.state.config) {
+        ProfileLockState::Running => return NativeResponse::error(
+            NativeErrorCode::ProfileInUse,
+            "This profile is in use and therefore cannot be deleted, close the profile and try again."
+        ),
+        ProfileLockState::Stale => log::trace!("Deleting profile that was not shut down properly: {:?}", profile_path),
+        ProfileLockState::Closed => {}
     }
 
-    // Delete profile files
-    fs::remove_dir_all(profile_path);
+    // Move profile files to the trash so the deletion can be undone
+    let order_position = OrderData::read(&context.state.config_dir).order
+        .iter()
+        .position(|id| id == &profile.id);
+    let trash_entry = match move_to_trash(&context.state.data_dir, &profile, &profile_path, order_position) {
+        Ok(e) => e,
+        Err(e) => return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to move profile to the trash!", e.to_string())
+    };
 
     // Make another profile the default
     if profile.default {
         if let Some(new_def_profile) = profiles.profile_entries.first_mut() {
-            new_def_profile.default = true
+            new_def_profile.default = true;
+            new_def_profile.revision += 1;
+        }
+    }
+
+    // Write new profile list, the profile is only deleted if the list no longer contains it
+    if let Err(e) = write_profiles(context.state, &profiles) {
+        if let Err(e) = take_from_trash(&context.state.data_dir, &trash_entry.id, &profile_path) {
+            log::error!("Failed to move profile {:?} back out of the trash: {}", profile_path, e);
         }
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
     }
 
     // Re-calculate profile order
     OrderData::try_rewrite(context, &profiles);
-
-    // Write new profile list
-    if let Err(e) = write_profiles(&context.state.config, &context.state.config_dir, &profiles) {
-        return NativeResponse::error_with_dbg_msg("Failed to save new changes!", e);
-    }
     notify_profile_changed(context, &profiles);
 
-    return NativeResponse::success(NativeResponseData::ProfileDeleted)
+    return NativeResponse::success(NativeResponseData::ProfileDeleted {
+        trash_id: trash_entry.id
+    })
 }
 
@@ -0,0 +1,94 @@
+use std::fs;
+use std::path::Path;
+use crate::AppContext;
+use crate::cmd::{check_profile_closed, lock_stores};
+use crate::cmd::create_profile::{inject_switcher_extension, notify_new_profile, register_new_profile};
+use crate::cmd::launch_profile::launch_error_response;
+use crate::ephemeral::{mark_ephemeral, new_ephemeral_profile, remove_ephemeral_profile};
+use crate::ipc::notify_profile_changed;
+use crate::native_req::NativeMessageLaunchEphemeralProfile;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::process::{fork_browser_proc, spawn_ephemeral_supervisor};
+use crate::profile_files::{copy_dir_filtered, is_portable_profile_file};
+use crate::profiles::ProfilesIniState;
+use crate::templates::apply_template;
+
+pub fn process_cmd_launch_ephemeral_profile(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageLaunchEphemeralProfile) -> NativeResponse {
+    let config = &context.state.config;
+    let source_path = match &msg.source_profile_id {
+        Some(source_id) => match profiles.profile_entries.iter().find(|p| &p.id == source_id) {
+            Some(p) => Some(p.full_path(config)),
+            None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+        },
+        None => None
+    };
+
+    let mut new_profile = new_ephemeral_profile(&profiles);
+    let new_profile_full_path = new_profile.full_path(config);
+
+    // Start from a copy of the source profile or from an empty profile with the switcher extension,
+    // the template is applied on top of either
+    let setup_result = match &source_path {
+        Some(source_path) => {
+            if let Err(e) = check_profile_closed(source_path, config, "copied") {
+                return e;
+            }
+            log::trace!("Copying profile {:?} to ephemeral profile {:?}", source_path, new_profile_full_path);
+            copy_dir_filtered(source_path, &new_profile_full_path, &is_portable_profile_file)
+                .map_err(|e| NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileCopyFailed, "Failed to copy profile!", e))
+        }
+        None => fs::create_dir_all(&new_profile_full_path)
+            .map(|_| inject_switcher_extension(context, &profiles, &new_profile_full_path))
+            .map_err(|e| NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileDirFailed, "Failed to create folder for new profile!", e))
+    }.and_then(|_| match &msg.template {
+        Some(template_id) => apply_template(&context.state.data_dir, template_id, &new_profile_full_path)
+            .map_err(|e| NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to apply template to new profile!", e.to_string())),
+        None => Ok(())
+    }).and_then(|_| mark_ephemeral(&new_profile_full_path)
+        .map_err(|e| NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileDirFailed, "Failed to create folder for new profile!", e)));
+    if let Err(e) = setup_result {
+        remove_setup_dir(&new_profile_full_path);
+        return e;
+    }
+
+    // Setting up the folder can take a while so the stores are only locked to register and launch
+    // the profile. The name must be unique so it is picked again from the current profile list.
+    let (_store_lock, mut profiles) = match lock_stores(context.state) {
+        Ok(l) => l,
+        Err(e) => {
+            remove_setup_dir(&new_profile_full_path);
+            return e;
+        }
+    };
+    new_profile.name = new_ephemeral_profile(&profiles).name;
+
+    let resp = match register_new_profile(context.state, &mut profiles, new_profile) {
+        Ok(resp) => {
+            notify_new_profile(context, &profiles);
+            resp
+        },
+        Err(e) => {
+            remove_setup_dir(&new_profile_full_path);
+            return e;
+        }
+    };
+
+    // Without a supervisor the profile would only be deleted the next time a connector starts
+    let launch_result = spawn_ephemeral_supervisor(&resp.id)
+        .and_then(|_| fork_browser_proc(context.state, profiles.profile_entries.last().unwrap(), msg.url));
+    if let Err(e) = launch_result {
+        if let Err(e) = remove_ephemeral_profile(context.state, &mut profiles, &resp.id) {
+            log::error!("Failed to remove ephemeral profile after failing to launch it: {:?}", e);
+        }
+        notify_profile_changed(context, &profiles);
+        return launch_error_response(e);
+    }
+
+    NativeResponse::success(NativeResponseData::EphemeralProfileLaunched { profile: resp })
+}
+
+fn remove_setup_dir(profile_dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(profile_dir) {
+        log::error!("Failed to clean up ephemeral profile folder: {:?}", e);
+    }
+}
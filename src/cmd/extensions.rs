@@ -0,0 +1,60 @@
+use crate::AppContext;
+use crate::cmd::check_profile_closed;
+use crate::extensions::{copy_extensions, list_extensions, sync_switcher_extension};
+use crate::native_req::{NativeMessageCopyExtensions, NativeMessageListExtensions};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::profiles::ProfilesIniState;
+
+pub fn process_cmd_list_extensions(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageListExtensions) -> NativeResponse {
+    let profile = match profiles.profile_entries.iter().find(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+
+    match list_extensions(&profile.full_path(&context.state.config)) {
+        Ok(extensions) => NativeResponse::success(NativeResponseData::Extensions { extensions }),
+        Err(e) => NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to read the extensions of the profile!", e.to_string())
+    }
+}
+
+pub fn process_cmd_copy_extensions(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageCopyExtensions) -> NativeResponse {
+    let config = &context.state.config;
+    let find_profile_path = |id: &str| profiles.profile_entries.iter()
+        .find(|p| p.id == id)
+        .map(|p| p.full_path(config))
+        .ok_or_else(|| NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!"));
+
+    let source_path = match find_profile_path(&msg.source_profile_id) {
+        Ok(p) => p,
+        Err(e) => return e
+    };
+
+    // Check every target before copying so a busy profile does not leave the others half done.
+    // The browser overwrites extensions.json when it exits so targets must be closed.
+    let mut target_paths = Vec::new();
+    for target_id in msg.target_profile_ids.iter().filter(|id| **id != msg.source_profile_id) {
+        let target_path = match find_profile_path(target_id) {
+            Ok(p) => p,
+            Err(e) => return e
+        };
+        if let Err(e) = check_profile_closed(&target_path, config, "modified") {
+            return e;
+        }
+        target_paths.push(target_path);
+    }
+
+    for target_path in target_paths {
+        if let Err(e) = copy_extensions(&source_path, &target_path, &msg.extension_ids) {
+            return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to copy extensions!", e.to_string())
+        }
+    }
+
+    NativeResponse::success(NativeResponseData::ExtensionsCopied)
+}
+
+pub fn process_cmd_sync_switcher_extension(context: &AppContext, profiles: ProfilesIniState) -> NativeResponse {
+    match sync_switcher_extension(context.state, &profiles) {
+        Ok(results) => NativeResponse::success(NativeResponseData::SwitcherExtensionSynced { results }),
+        Err(e) => NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to sync the switcher extension!", e.to_string())
+    }
+}
@@ -4,23 +4,23 @@ use ulid::{Ulid};
 use crate::{AppContext, NativeResponse};
 use crate::avatars::encode_avatar_to_string;
 use crate::native_req::NativeMessageGetAvatar;
-use crate::native_resp::NativeResponseData;
+use crate::native_resp::{NativeErrorCode, NativeResponseData};
 
 pub fn process_cmd_get_avatar(context: &AppContext, msg: NativeMessageGetAvatar) -> NativeResponse {
     let ulid = match Ulid::from_str(&msg.avatar) {
         Ok(u) => u,
-        Err(e) => return NativeResponse::error_with_dbg_msg("Failed to parse avatar ID.", e),
+        Err(e) => return NativeResponse::error_with_dbg_msg(NativeErrorCode::AvatarNotFound, "Failed to parse avatar ID.", e),
     };
     let avatar_path = {
         let avatars_read_lock = context.avatars.read().unwrap();
         match avatars_read_lock.get(&ulid) {
             Some(p) => p.clone(),
-            None => return NativeResponse::error("Avatar not found!")
+            None => return NativeResponse::error(NativeErrorCode::AvatarNotFound, "Avatar not found!")
         }
     };
     let avatar_data = match fs::read(&avatar_path) {
         Ok(d) => d,
-        Err(e) => return NativeResponse::error_with_dbg_msg("Failed to load avatar.", e)
+        Err(e) => return NativeResponse::error_with_dbg_msg(NativeErrorCode::AvatarStoreFailed, "Failed to load avatar.", e)
     };
     let mime = match avatar_path
         .extension()
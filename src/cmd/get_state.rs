@@ -0,0 +1,43 @@
+use crate::AppContext;
+use crate::avatars::list_avatars;
+use crate::profiles::read_profiles;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData, NativeResponseProfileListProfileEntry};
+use crate::options::read_global_options;
+use crate::profiles_order::OrderData;
+use crate::storage::{custom_avatars_path, global_options_data_path, read_state_revision};
+use crate::locking::StoreLock;
+use crate::cmd::store_busy_error;
+
+pub fn process_cmd_get_state(context: &AppContext) -> NativeResponse {
+    let state = context.state;
+
+    // Hold the lock while reading so the snapshot does not mix stores from before and after a
+    // change made by another window, and the revision matches the snapshot
+    let _store_lock = match StoreLock::acquire(&state.data_dir) {
+        Ok(l) => l,
+        Err(e) => return store_busy_error(e)
+    };
+    let revision = read_state_revision(&state.data_dir);
+    let profiles = match read_profiles(&state.config, &state.config_dir) {
+        Ok(p) => p,
+        Err(e) => return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to load profile list.", e)
+    };
+
+    let options = read_global_options(&global_options_data_path(&state.config_dir));
+    let avatars = list_avatars(&custom_avatars_path(&context.state.data_dir))
+        .keys()
+        .map(|u| u.to_string())
+        .collect();
+    let order_data = OrderData::read(&state.config_dir);
+
+    NativeResponse::success(NativeResponseData::State {
+        current_profile_id: state.cur_profile_id.clone(),
+        profiles: profiles.profile_entries.iter().map(NativeResponseProfileListProfileEntry::from_profile_entry).collect(),
+        options,
+        avatars,
+        order: order_data.order,
+        order_revision: order_data.revision,
+        revision,
+        warnings: profiles.warnings(&state.config)
+    })
+}
@@ -1,49 +1,48 @@
 use crate::state::AppState;
 use crate::profiles::{ProfilesIniState, write_profiles};
+use crate::profiles_order::OrderData;
 use crate::native_req::NativeMessageInitialize;
-use crate::native_resp::{NativeResponse, NativeResponseData, NativeResponseEvent, NativeResponseProfileListProfileEntry, write_native_event};
-use std::{fs};
+use crate::native_resp::{NativeErrorCode, NativeErrorDetails, NativeResponse, NativeResponseData, NativeResponseEvent, NativeResponseProfileListProfileEntry, write_native_event};
 use semver::Version;
 use crate::options::native_notify_updated_options;
+use crate::trash::purge_expired_trash;
+use crate::ephemeral::collect_ephemeral_profiles;
+use crate::prefs::sync_pending_prefs;
+use crate::extensions::sync_switcher_extension;
+use crate::profile_detection::{detect_current_profile, ProfileDetection};
+use crate::versions::{ConnectorCapabilities, ExtensionCompat, set_extension_compat};
 
 pub fn process_cmd_initialize(app_state: &mut AppState,
                               mut profiles: ProfilesIniState,
-                              msg: NativeMessageInitialize) -> NativeResponse {
+                              msg: NativeMessageInitialize,
+                              stores_locked: bool) -> NativeResponse {
     if let Some(profile_id) = &msg.profile_id {
         log::trace!("Profile ID was provided by extension: {}", profile_id);
-        finish_init(app_state, &mut profiles, profile_id, msg.extension_id, msg.extension_version);
-        return NativeResponse::success(NativeResponseData::Initialized { cached: true })
+        finish_init(app_state, &mut profiles, profile_id, msg.extension_id, msg.extension_version, msg.features.as_deref(), stores_locked);
+        return NativeResponse::success(NativeResponseData::Initialized {
+            cached: true,
+            capabilities: ConnectorCapabilities::current()
+        })
     }
 
     // Extension didn't tell us profile id so we have to determine it
     log::trace!("Profile ID was not provided by extension, determining using ext id ({})", msg.extension_id);
 
-    // Search every profile
-    for profile in &profiles.profile_entries {
-        let mut storage_path = profile.full_path(&app_state.config);
-        storage_path.push("storage");
-        storage_path.push("default");
-
-        let ext_installed = match fs::read_dir(storage_path) {
-            Ok(p) => p,
-            Err(_) => continue // Skip profiles that do not have valid storage dir
-        }.filter_map(|it| match it {
-            Ok(entry) => Some(entry),
-            Err(_) => None
-        }).any(|it| it.file_name()
-            .to_string_lossy()
-            .starts_with(&("moz-extension+++".to_owned() + &msg.extension_id))
-        );
-
-        if ext_installed {
-            let profile_id = profile.id.clone();
-            log::trace!("Profile ID determined: {}", profile_id);
-            finish_init(app_state, &mut profiles, &profile_id, msg.extension_id, msg.extension_version);
-            return NativeResponse::success(NativeResponseData::Initialized { cached: false })
+    match detect_current_profile(&profiles, &app_state.config, &msg.extension_id) {
+        ProfileDetection::Found(profile_id) => {
+            finish_init(app_state, &mut profiles, &profile_id, msg.extension_id, msg.extension_version, msg.features.as_deref(), stores_locked);
+            NativeResponse::success(NativeResponseData::Initialized {
+                cached: false,
+                capabilities: ConnectorCapabilities::current()
+            })
         }
+        ProfileDetection::Ambiguous(profile_ids) => NativeResponse::error_with_details(
+            NativeErrorCode::ProfileDetectionAmbiguous,
+            "Unable to detect current profile, multiple profiles match.",
+            NativeErrorDetails::AmbiguousProfiles { matching_profile_ids: profile_ids }
+        ),
+        ProfileDetection::NotFound => NativeResponse::error(NativeErrorCode::ProfileDetectionFailed, "Unable to detect current profile.")
     }
-
-    return NativeResponse::error("Unable to detect current profile.")
 }
 
 fn finish_init(
@@ -52,38 +51,89 @@ fn finish_init(
     profile_id: &str,
     internal_ext_id: String,
     ext_version: Option<String>,
+    ext_features: Option<&[String]>,
+    stores_locked: bool,
 ) {
+    // The extension may have cached the path based id the profile had before it got its own id, the
+    // current profile may also be a copy that only gets its own id now
+    if stores_locked {
+        profiles.repair_profile_ids(&app_state.config);
+    }
+    let profile_id = profiles.canonical_profile_id(profile_id).unwrap_or(profile_id).to_owned();
+    let profile_id = profile_id.as_str();
+
     app_state.cur_profile_id = Some(profile_id.to_owned());
     app_state.internal_extension_id = Some(internal_ext_id);
     app_state.extension_version = ext_version.and_then(|v| Version::parse(&v).ok());
+    set_extension_compat(ExtensionCompat::new(app_state.extension_version.clone(), ext_features));
 
-    if app_state.first_run {
+    if app_state.first_run && stores_locked {
         app_state.first_run = false;
         log::trace!("First run!");
 
         match profiles.profile_entries.iter_mut().find(|p| p.id == profile_id) {
             Some(profile) => {
                 // Set first-run profile as default
-                profile.default = true;
-                for other_profile in profiles.profile_entries.iter_mut() {
-                    if other_profile.id != profile_id {
-                        other_profile.default = false
-                    }
+                if !profile.default {
+                    profile.default = true;
+                    profile.revision += 1;
                 }
+                profiles.clear_other_defaults(profile_id);
 
-                write_profiles(&app_state.config, &app_state.config_dir, profiles);
+                if let Err(e) = write_profiles(app_state, profiles) {
+                    log::error!("Failed to set first-run profile as default: {:?}", e);
+                }
             }
             None => log::error!("Failed to find first-run profile to set as default: {}", profile_id)
         }
     }
 
+    // These write the stores so they wait for the next initialization if the lock was not taken
+    if stores_locked {
+        migrate_path_ids(app_state, profiles);
+        collect_ephemeral_profiles(app_state, profiles);
+        sync_pending_prefs(&app_state.config_dir, &app_state.config, profiles);
+        if app_state.config.sync_switcher_extension() {
+            if let Err(e) = sync_switcher_extension(app_state, profiles) {
+                log::error!("Failed to sync switcher extension: {:?}", e);
+            }
+        }
+    }
+
     // Notify extension of new profile list
     write_native_event(NativeResponseEvent::ProfileList {
         current_profile_id: profile_id.to_owned(),
-        profiles: profiles.profile_entries.iter().map(NativeResponseProfileListProfileEntry::from_profile_entry).collect()
+        profiles: profiles.profile_entries.iter().map(NativeResponseProfileListProfileEntry::from_profile_entry).collect(),
+        warnings: profiles.warnings(&app_state.config)
     });
 
     // Notify extension of current options
     native_notify_updated_options(app_state);
+
+    if stores_locked {
+        purge_expired_trash(app_state);
+    }
 }
 
+
+// Move metadata and profile order entries stored under path based ids to the profile ids
+fn migrate_path_ids(app_state: &AppState, profiles: &ProfilesIniState) {
+    if profiles.has_path_id_metadata() {
+        log::info!("Migrating profile metadata to new profile ids");
+        if let Err(e) = write_profiles(app_state, profiles) {
+            log::error!("Failed to migrate profile metadata: {:?}", e);
+        }
+    }
+
+    let mut order_data = OrderData::read(&app_state.config_dir);
+    let has_path_ids = order_data.order.iter()
+        .any(|id| profiles.canonical_profile_id(id).is_some_and(|c| c != id));
+    if has_path_ids {
+        log::info!("Migrating profile order to new profile ids");
+        order_data.recalculate(profiles);
+        order_data.revision += 1;
+        if let Err(e) = order_data.write(app_state) {
+            log::error!("Failed to migrate profile order: {:?}", e);
+        }
+    }
+}
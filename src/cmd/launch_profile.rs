@@ -1,9 +1,11 @@
 use crate::AppContext;
 use crate::profiles::ProfilesIniState;
 use crate::native_req::NativeMessageLaunchProfile;
-use crate::native_resp::{NativeResponse, NativeResponseData};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
 use crate::ipc::notify_focus_window;
 use crate::process::{fork_browser_proc, ForkBrowserProcError};
+use crate::prefs::{write_managed_prefs, PrefsData};
+use crate::extensions::sync_addon;
 
 pub fn process_cmd_launch_profile(context: &AppContext,
                               profiles: ProfilesIniState,
@@ -11,7 +13,7 @@ pub fn process_cmd_launch_profile(context: &AppContext,
     // Match ID with profile
     let profile = match profiles.profile_entries.iter().find(|p| p.id == msg.profile_id) {
         Some(p) => p,
-        None => return NativeResponse::error("No profile with the specified id could be found!")
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
     };
 
     log::trace!("Launching profile: {}", profile.id);
@@ -21,20 +23,53 @@ pub fn process_cmd_launch_profile(context: &AppContext,
         Err(e) => { log::info!("Failed to focus current browser window, launching new window: {:?}", e); }
     }
 
+    // The profile is closed so prefs that changed while it was running can be written now. The
+    // store is not locked here, the profile stays pending until the next sync.
+    let prefs_data = PrefsData::read(&context.state.config_dir);
+    if prefs_data.pending.contains(&profile.id) {
+        if let Err(e) = write_managed_prefs(&profile.full_path(&context.state.config), &prefs_data.effective_prefs(&profile.id)) {
+            log::error!("Failed to write pending prefs of profile {}: {:?}", profile.id, e);
+        }
+    }
+
+    // Profiles that were running when the connector started missed the sync
+    if context.state.config.sync_switcher_extension() {
+        sync_launched_profile_switcher(context, &profiles, &profile.id);
+    }
+
     match fork_browser_proc(context.state, profile, msg.url) {
         Ok(_) => NativeResponse::success(NativeResponseData::ProfileLaunched),
-        Err(e) => match e {
-            ForkBrowserProcError::BadExitCode => NativeResponse::error_with_dbg_msg("Failed to launch browser with new profile (bad exit code)!", e),
-            ForkBrowserProcError::ForkError { .. } => NativeResponse::error_with_dbg_msg("Failed to launch browser with new profile (fork error)!", e),
-            ForkBrowserProcError::ProcessLaunchError(_) => NativeResponse::error_with_dbg_msg("Failed to launch browser with new profile!", e),
-            ForkBrowserProcError::BinaryNotFound => NativeResponse::error_with_dbg_msg("Unable to find browser binary!", e),
-            ForkBrowserProcError::BinaryDoesNotExist => NativeResponse::error(concat!(
+        Err(e) => launch_error_response(e)
+    }
+}
+
+fn sync_launched_profile_switcher(context: &AppContext, profiles: &ProfilesIniState, profile_id: &str) {
+    let state = context.state;
+    let (extension_id, cur_profile_id) = match (&state.extension_id, &state.cur_profile_id) {
+        (Some(e), Some(p)) => (e, p),
+        _ => return
+    };
+    let find_path = |id: &str| profiles.profile_entries.iter().find(|p| p.id == id).map(|p| p.full_path(&state.config));
+    if let (Some(cur_profile_path), Some(profile_path)) = (find_path(cur_profile_id), find_path(profile_id)) {
+        if let Err(e) = sync_addon(&cur_profile_path, extension_id, std::iter::once((profile_id, profile_path)), &state.config) {
+            log::error!("Failed to sync switcher extension into launched profile: {:?}", e);
+        }
+    }
+}
+
+pub fn launch_error_response(e: ForkBrowserProcError) -> NativeResponse {
+    let code = NativeErrorCode::from(&e);
+    match e {
+        ForkBrowserProcError::BadExitCode => NativeResponse::error_with_dbg_msg(code, "Failed to launch browser with new profile (bad exit code)!", e),
+        ForkBrowserProcError::ForkError { error_message } => NativeResponse::error_with_dbg_str(code, "Failed to launch browser with new profile (fork error)!", error_message),
+        ForkBrowserProcError::ProcessLaunchError(err) => NativeResponse::error_with_dbg_msg(code, "Failed to launch browser with new profile!", err),
+        ForkBrowserProcError::BinaryNotFound => NativeResponse::error_with_dbg_msg(code, "Unable to find browser binary!", e),
+        ForkBrowserProcError::BinaryDoesNotExist => NativeResponse::error(code, concat!(
             "The version of your browser that is currently running can no longer be found. ",
             "This is usually because your browser has updated but you haven't restarted your browser recently to apply the update. ",
             "Please restart your browser to resolve this issue."
-            )),
-            ForkBrowserProcError::COMError { .. } => NativeResponse::error_with_dbg_msg("Failed to launch browser with new profile (Windows COM error)!", e),
-            ForkBrowserProcError::MSIXProcessLaunchError { .. } => NativeResponse::error_with_dbg_msg("Failed to launch browser with new profile (Windows AAM error)!", e),
-        }
+        )),
+        ForkBrowserProcError::COMError { error_message } => NativeResponse::error_with_dbg_str(code, "Failed to launch browser with new profile (Windows COM error)!", error_message),
+        ForkBrowserProcError::MSIXProcessLaunchError { error_message } => NativeResponse::error_with_dbg_str(code, "Failed to launch browser with new profile (Windows AAM error)!", error_message),
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,6 @@
 mod initialize;
 mod launch_profile;
-mod create_profile;
+pub mod create_profile;
 mod delete_profile;
 mod update_profile;
 mod update_options;
@@ -9,10 +9,23 @@ mod add_avatars;
 mod get_avatar;
 mod delete_avatar;
 mod update_profiles_order;
+mod batch;
+mod get_state;
+mod backups;
+mod repair_profiles_ini;
+mod clone_profile;
+mod profile_archives;
+mod trash;
+mod profile_scan;
+mod move_profile;
+mod ephemeral_profile;
+mod templates;
+mod prefs;
+mod extensions;
 
 use crate::state::AppState;
 use crate::native_req::NativeMessage;
-use crate::native_resp::NativeResponse;
+use crate::native_resp::{NativeErrorCode, NativeResponse};
 use crate::cmd::initialize::process_cmd_initialize;
 use crate::cmd::launch_profile::process_cmd_launch_profile;
 use crate::cmd::create_profile::process_cmd_create_profile;
@@ -25,7 +38,24 @@ use crate::cmd::add_avatars::process_cmd_add_avatars;
 use crate::cmd::delete_avatar::process_cmd_delete_avatar;
 use crate::cmd::get_avatar::process_cmd_get_avatar;
 use crate::cmd::update_profiles_order::process_cmd_update_profiles_order;
-use crate::profiles::read_profiles;
+use crate::cmd::batch::process_cmd_batch;
+use crate::cmd::get_state::process_cmd_get_state;
+use crate::cmd::backups::{process_cmd_list_backups, process_cmd_restore_backup};
+use crate::cmd::repair_profiles_ini::process_cmd_repair_profiles_ini;
+use crate::cmd::clone_profile::process_cmd_clone_profile;
+use crate::cmd::profile_archives::{process_cmd_export_profile, process_cmd_import_profile};
+use crate::cmd::trash::{process_cmd_list_trash, process_cmd_purge_trash, process_cmd_restore_profile};
+use crate::cmd::profile_scan::{process_cmd_adopt_profile, process_cmd_remove_dangling_entry, process_cmd_scan_profiles};
+use crate::cmd::move_profile::process_cmd_move_profile;
+use crate::cmd::ephemeral_profile::process_cmd_launch_ephemeral_profile;
+use crate::cmd::templates::{process_cmd_delete_template, process_cmd_list_templates, process_cmd_save_profile_as_template};
+use crate::cmd::prefs::{process_cmd_get_prefs, process_cmd_remove_pref, process_cmd_set_pref};
+use crate::cmd::extensions::{process_cmd_copy_extensions, process_cmd_list_extensions, process_cmd_sync_switcher_extension};
+use crate::profiles::{read_profiles, ProfilesIniState};
+use crate::locking::{StoreLock, StoreLockError};
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
+use crate::config::Config;
+use std::path::Path;
 
 // === COMMANDS ===
 
@@ -34,25 +64,120 @@ macro_rules! profiles {
         match read_profiles(&$app_state.config, &$app_state.config_dir) {
             Ok(p) => p,
             Err(e) => {
-                return NativeResponse::error_with_dbg_msg("Failed to load profile list.", e);
+                return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to load profile list.", e);
             }
         }
     };
 }
 
+fn store_busy_error(e: StoreLockError) -> NativeResponse {
+    NativeResponse::error_with_dbg_str(
+        NativeErrorCode::StoreBusy,
+        "Another window is currently saving changes to your profiles, please try again.",
+        e.to_string()
+    )
+}
+
+/// Take the store lock and read the profile list again, commands that do slow work without holding
+/// the lock must not save a profile list that was read before it was taken
+fn lock_stores(state: &AppState) -> Result<(StoreLock, ProfilesIniState), NativeResponse> {
+    let store_lock = StoreLock::acquire(&state.data_dir).map_err(store_busy_error)?;
+    let profiles = read_profiles(&state.config, &state.config_dir)
+        .map_err(|e| NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to load profile list.", e))?;
+    Ok((store_lock, profiles))
+}
+
 pub fn execute_init_cmd(app_state: &mut AppState,
                         msg: NativeMessage) -> NativeResponse {
+    // Initialization may make the current profile the default on the first run and migrates
+    // metadata stored under path based profile ids. Another window may be copying a profile so
+    // the extension is still initialized if the lock cannot be taken, only the writes are skipped.
+    let store_lock = match StoreLock::acquire(&app_state.data_dir) {
+        Ok(l) => Some(l),
+        Err(e) => {
+            log::warn!("Failed to lock stores during initialization, skipping maintenance: {:?}", e);
+            None
+        }
+    };
     match msg {
-        NativeMessage::Initialize(msg) => process_cmd_initialize(app_state, profiles!(app_state), msg),
-        _ => NativeResponse::error_with_dbg_str("Connector is not ready yet!", "Connector has not been initialized.".to_owned())
+        NativeMessage::Initialize(msg) => process_cmd_initialize(app_state, profiles!(app_state), msg, store_lock.is_some()),
+        _ => NativeResponse::error_with_dbg_str(NativeErrorCode::NotInitialized, "Connector is not ready yet!", "Connector has not been initialized.".to_owned())
+    }
+}
+
+/// Whether the command reads, modifies and writes the profile stores, the store lock is then held
+/// for the whole command by `execute_cmd_for_message`. The other commands fall into two groups:
+/// - CloneProfile, ImportProfile, MoveProfile, LaunchEphemeralProfile, AddAvatars and GetState take
+///   the lock themselves (usually through `lock_stores`) so slow work such as copying a profile or
+///   waiting for a file picker does not block other windows. They must not be listed here as the
+///   lock is not reentrant, taking it again on the same thread fails once the lock times out.
+/// - SaveProfileAsTemplate, CopyExtensions and the remaining commands only read the stores or
+///   write files that are not covered by the lock (templates, profile folders).
+fn modifies_stores(msg: &NativeMessage) -> bool {
+    matches!(msg,
+        NativeMessage::CreateProfile(_)
+        | NativeMessage::DeleteProfile(_)
+        | NativeMessage::UpdateProfile(_)
+        | NativeMessage::UpdateOptions(_)
+        | NativeMessage::DeleteAvatar(_)
+        | NativeMessage::UpdateProfileOrder(_)
+        | NativeMessage::Batch(_)
+        | NativeMessage::RestoreBackup(_)
+        | NativeMessage::RepairProfilesIni
+        | NativeMessage::RestoreProfile(_)
+        | NativeMessage::PurgeTrash(_)
+        | NativeMessage::AdoptProfile(_)
+        | NativeMessage::RemoveDanglingEntry(_)
+        | NativeMessage::SetPref(_)
+        | NativeMessage::RemovePref(_))
+}
+
+/// Fail with a conflict error if the caller expected a different revision than the current one
+pub fn check_expected_revision(expected: Option<u64>, current: u64) -> Result<(), NativeResponse> {
+    match expected {
+        Some(expected) if expected != current => Err(NativeResponse::error_with_dbg_str(
+            NativeErrorCode::RevisionConflict,
+            "This was changed somewhere else in the meantime, please reload and try again.",
+            format!("Expected revision {} but current revision is {}", expected, current)
+        )),
+        _ => Ok(())
+    }
+}
+
+/// Fail if the profile is in use or was not shut down properly, copying the profile in either
+/// state may result in corrupted databases
+pub fn check_profile_closed(profile_path: &Path, config: &Config, action: &str) -> Result<(), NativeResponse> {
+    match detect_profile_lock_state(profile_path, config) {
+        ProfileLockState::Closed => Ok(()),
+        ProfileLockState::Running => Err(NativeResponse::error(
+            NativeErrorCode::ProfileInUse,
+            format!("This profile is in use and therefore cannot be {}, close the profile and try again.", action)
+        )),
+        ProfileLockState::Stale => Err(NativeResponse::error(
+            NativeErrorCode::ProfileNotShutDown,
+            format!(concat!(
+                "This profile cannot be {} as your browser did not shut it down properly the last time you used it. ",
+                "Open and close the profile to resolve this issue, then try again."
+            ), action)
+        ))
     }
 }
 
 pub fn execute_cmd_for_message(context: &AppContext,
                                msg: NativeMessage) -> NativeResponse {
     let state = context.state;
+    // Commands are executed concurrently (and by other connectors), make sure they don't overwrite
+    // each other's changes
+    let _store_lock = if modifies_stores(&msg) {
+        match StoreLock::acquire(&state.data_dir) {
+            Ok(l) => Some(l),
+            Err(e) => return store_busy_error(e)
+        }
+    } else {
+        None
+    };
     match msg {
-        NativeMessage::Initialize(_) => NativeResponse::error("Connector cannot be initialized multiple times!"),
+        NativeMessage::Initialize(_) => NativeResponse::error(NativeErrorCode::AlreadyInitialized, "Connector cannot be initialized multiple times!"),
         NativeMessage::LaunchProfile(msg) => process_cmd_launch_profile(context, profiles!(state), msg),
         NativeMessage::CreateProfile(msg) => process_cmd_create_profile(context, profiles!(state), msg),
         NativeMessage::DeleteProfile(msg) => process_cmd_delete_profile(context, profiles!(state), msg),
@@ -62,6 +187,31 @@ pub fn execute_cmd_for_message(context: &AppContext,
         NativeMessage::AddAvatars => process_cmd_add_avatars(context, profiles!(state)),
         NativeMessage::GetAvatar(msg) => process_cmd_get_avatar(context, msg),
         NativeMessage::DeleteAvatar(msg) => process_cmd_delete_avatar(context, profiles!(state), msg),
-        NativeMessage::UpdateProfileOrder(msg) => process_cmd_update_profiles_order(context, profiles!(state), msg)
+        NativeMessage::UpdateProfileOrder(msg) => process_cmd_update_profiles_order(context, profiles!(state), msg),
+        NativeMessage::Batch(msg) => process_cmd_batch(context, profiles!(state), msg),
+        NativeMessage::GetState => process_cmd_get_state(context),
+        NativeMessage::ListBackups => process_cmd_list_backups(context),
+        NativeMessage::RestoreBackup(msg) => process_cmd_restore_backup(context, msg),
+        NativeMessage::RepairProfilesIni => process_cmd_repair_profiles_ini(context, profiles!(state)),
+        NativeMessage::CloneProfile(msg) => process_cmd_clone_profile(context, profiles!(state), msg),
+        NativeMessage::ExportProfile(msg) => process_cmd_export_profile(context, profiles!(state), msg),
+        NativeMessage::ImportProfile(msg) => process_cmd_import_profile(context, msg),
+        NativeMessage::ListTrash => process_cmd_list_trash(context),
+        NativeMessage::RestoreProfile(msg) => process_cmd_restore_profile(context, profiles!(state), msg),
+        NativeMessage::PurgeTrash(msg) => process_cmd_purge_trash(context, msg),
+        NativeMessage::ScanProfiles => process_cmd_scan_profiles(context, profiles!(state)),
+        NativeMessage::AdoptProfile(msg) => process_cmd_adopt_profile(context, profiles!(state), msg),
+        NativeMessage::RemoveDanglingEntry(msg) => process_cmd_remove_dangling_entry(context, profiles!(state), msg),
+        NativeMessage::MoveProfile(msg) => process_cmd_move_profile(context, msg),
+        NativeMessage::LaunchEphemeralProfile(msg) => process_cmd_launch_ephemeral_profile(context, profiles!(state), msg),
+        NativeMessage::ListTemplates => process_cmd_list_templates(context),
+        NativeMessage::SaveProfileAsTemplate(msg) => process_cmd_save_profile_as_template(context, profiles!(state), msg),
+        NativeMessage::DeleteTemplate(msg) => process_cmd_delete_template(context, msg),
+        NativeMessage::GetPrefs(msg) => process_cmd_get_prefs(context, profiles!(state), msg),
+        NativeMessage::SetPref(msg) => process_cmd_set_pref(context, profiles!(state), msg),
+        NativeMessage::RemovePref(msg) => process_cmd_remove_pref(context, profiles!(state), msg),
+        NativeMessage::ListExtensions(msg) => process_cmd_list_extensions(context, profiles!(state), msg),
+        NativeMessage::CopyExtensions(msg) => process_cmd_copy_extensions(context, profiles!(state), msg),
+        NativeMessage::SyncSwitcherExtension => process_cmd_sync_switcher_extension(context, profiles!(state))
     }
 }
\ No newline at end of file
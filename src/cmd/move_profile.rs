@@ -0,0 +1,222 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use ulid::Ulid;
+use crate::AppContext;
+use crate::cmd::{check_expected_revision, check_profile_closed, lock_stores};
+use crate::ipc::notify_profile_changed;
+use crate::native_req::NativeMessageMoveProfile;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData, NativeResponseProfileListProfileEntry};
+use crate::profile_files::copy_dir_all;
+use crate::profile_identity::{read_profile_id_marker, write_profile_id_marker};
+use crate::profiles::write_profiles;
+
+pub fn process_cmd_move_profile(context: &AppContext, msg: NativeMessageMoveProfile) -> NativeResponse {
+    let config = &context.state.config;
+    let (store_lock, mut profiles) = match lock_stores(context.state) {
+        Ok(l) => l,
+        Err(e) => return e
+    };
+    let profile_index = match profiles.profile_entries.iter().position(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+    let profile = &profiles.profile_entries[profile_index];
+
+    if let Err(e) = check_expected_revision(msg.expected_revision, profile.revision) {
+        return e;
+    }
+
+    let old_full_path = profile.full_path(config);
+    if let Err(e) = check_profile_closed(&old_full_path, config, "moved") {
+        return e;
+    }
+
+    let browser_profile_dir = config.browser_profile_dir();
+    let (new_path, new_is_relative) = match msg.target {
+        Some(target) => {
+            let target = PathBuf::from(target);
+            if !target.is_absolute() || target.components().any(|c| c == Component::ParentDir) {
+                return NativeResponse::error(NativeErrorCode::InvalidMoveTarget, "The new location must be a full path.")
+            }
+            // Profiles in the browser profile dir are stored relative to it
+            match target.strip_prefix(&browser_profile_dir) {
+                Ok(relative) => (to_ini_path(relative), true),
+                Err(_) => (target.to_string_lossy().into_owned(), false)
+            }
+        }
+        None => {
+            // Move the profile back into the browser profile dir, keeping the folder name if possible
+            let dir_name = old_full_path.file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .filter(|n| !browser_profile_dir.join(n).exists())
+                .unwrap_or_else(|| "profile-".to_owned() + &Ulid::new().to_string());
+            (dir_name, true)
+        }
+    };
+
+    let new_full_path = if new_is_relative {
+        browser_profile_dir.join(&new_path)
+    } else {
+        PathBuf::from(&new_path)
+    };
+    if new_path == profile.path && new_is_relative == profile.is_relative {
+        return NativeResponse::error(NativeErrorCode::InvalidMoveTarget, "The profile is already stored in this location.")
+    }
+    if new_full_path.exists() || profiles.profile_entries.iter().any(|p| p.full_path(config) == new_full_path) {
+        return move_target_exists_error()
+    }
+    // Symlinks could hide that one folder is inside the other
+    let (old_real_path, new_real_path) = (canonicalize_existing(&old_full_path), canonicalize_existing(&new_full_path));
+    if new_real_path.starts_with(&old_real_path) || old_real_path.starts_with(&new_real_path) {
+        return NativeResponse::error(NativeErrorCode::InvalidMoveTarget, "A profile cannot be moved into its own folder or one of the folders containing it.")
+    }
+
+    // The id of the profile is stored in its folder so it is kept after the move, profiles whose
+    // folder could not be written before still use their path based id
+    if read_profile_id_marker(&old_full_path).as_ref() != Some(&profile.id) {
+        if let Err(e) = write_profile_id_marker(&old_full_path, &profile.id) {
+            return NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileMoveFailed, "Failed to move profile!", e);
+        }
+    }
+
+    if let Some(parent) = new_full_path.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            return NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileMoveFailed, "Failed to move profile!", e);
+        }
+    }
+
+    log::trace!("Moving profile {:?} to {:?}", old_full_path, new_full_path);
+    if let Err(e) = fs::rename(&old_full_path, &new_full_path) {
+        // The folder is on another drive, it is copied without holding the store lock as that may
+        // take a while and the original is only deleted once profiles.ini points to the copy
+        log::trace!("Failed to rename {:?} to {:?}, copying instead: {:?}", old_full_path, new_full_path, e);
+        let revision = profile.revision;
+        drop(store_lock);
+        return copy_profile_to(context, &msg.profile_id, revision, &old_full_path, &new_full_path, new_path, new_is_relative);
+    }
+
+    update_id_marker(&new_full_path, &msg.profile_id);
+    profiles.relocate_profile(profile_index, new_path, new_is_relative);
+
+    if let Err(e) = write_profiles(context.state, &profiles) {
+        // Put the profile back where profiles.ini expects it
+        if let Err(e) = fs::rename(&new_full_path, &old_full_path) {
+            log::error!("Failed to move profile back after failing to save changes: {:?}", e);
+        }
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
+    }
+    notify_profile_changed(context, &profiles);
+
+    NativeResponse::success(NativeResponseData::ProfileMoved {
+        profile: NativeResponseProfileListProfileEntry::from_profile_entry(&profiles.profile_entries[profile_index])
+    })
+}
+
+fn copy_profile_to(context: &AppContext,
+                   profile_id: &str,
+                   revision: u64,
+                   old_full_path: &Path,
+                   new_full_path: &Path,
+                   new_path: String,
+                   new_is_relative: bool) -> NativeResponse {
+    let config = &context.state.config;
+    let remove_copy = || if let Err(e) = fs::remove_dir_all(new_full_path) {
+        log::error!("Failed to clean up partially moved profile: {:?}", e);
+    };
+
+    // Claim the new location first so that we never copy into (or later delete) a folder that
+    // something else created while the stores were not locked
+    match fs::create_dir(new_full_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return move_target_exists_error(),
+        Err(e) => return NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileMoveFailed, "Failed to move profile!", e)
+    }
+
+    if let Err(e) = copy_dir_all(old_full_path, new_full_path) {
+        remove_copy();
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::ProfileMoveFailed, "Failed to move profile!", e);
+    }
+
+    let (_store_lock, mut profiles) = match lock_stores(context.state) {
+        Ok(l) => l,
+        Err(e) => {
+            remove_copy();
+            return e;
+        }
+    };
+    // The profile may have been changed, moved or started while it was copied
+    let profile_index = match profiles.profile_entries.iter().position(|p| p.id == profile_id) {
+        Some(p) => p,
+        None => {
+            remove_copy();
+            return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+        }
+    };
+    if let Err(e) = check_expected_revision(Some(revision), profiles.profile_entries[profile_index].revision)
+        .and_then(|_| check_profile_closed(old_full_path, config, "moved")) {
+        remove_copy();
+        return e;
+    }
+    // Another profile may have been registered in the new location in the meantime
+    if profiles.profile_entries.iter().any(|p| p.id != profile_id && p.full_path(config) == new_full_path) {
+        remove_copy();
+        return move_target_exists_error();
+    }
+
+    update_id_marker(new_full_path, profile_id);
+    profiles.relocate_profile(profile_index, new_path, new_is_relative);
+
+    if let Err(e) = write_profiles(context.state, &profiles) {
+        remove_copy();
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
+    }
+    notify_profile_changed(context, &profiles);
+
+    // The profile was moved even if we cannot clean up the original
+    if let Err(e) = fs::remove_dir_all(old_full_path) {
+        log::warn!("Failed to delete {:?} after copying it to {:?}: {:?}", old_full_path, new_full_path, e);
+    }
+
+    NativeResponse::success(NativeResponseData::ProfileMoved {
+        profile: NativeResponseProfileListProfileEntry::from_profile_entry(&profiles.profile_entries[profile_index])
+    })
+}
+
+fn move_target_exists_error() -> NativeResponse {
+    NativeResponse::error(NativeErrorCode::MoveTargetExists, "Something else is already stored in the new location, please choose another location.")
+}
+
+// The marker still names the old folder. The id is kept either way, but a copied folder would be
+// treated as a copy of the profile until the old folder is deleted.
+fn update_id_marker(profile_dir: &Path, profile_id: &str) {
+    if let Err(e) = write_profile_id_marker(profile_dir, profile_id) {
+        log::warn!("Failed to update id marker of moved profile {:?}: {:?}", profile_dir, e);
+    }
+}
+
+// Resolve symlinks in the part of the path that exists, the new location does not exist yet
+fn canonicalize_existing(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(real_path) = fs::canonicalize(existing) {
+            return missing.iter().rev().fold(real_path, |p, c| p.join(c));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return path.to_owned()
+        }
+    }
+}
+
+// profiles.ini always uses forward slashes
+fn to_ini_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
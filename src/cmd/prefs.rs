@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use crate::AppContext;
+use crate::native_req::{NativeMessageGetPrefs, NativeMessageRemovePref, NativeMessageSetPref};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::prefs::{read_current_prefs, validate_pref, PrefsData};
+use crate::profiles::ProfilesIniState;
+
+pub fn process_cmd_get_prefs(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageGetPrefs) -> NativeResponse {
+    let profile = match profiles.profile_entries.iter().find(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+
+    let prefs_data = PrefsData::read(&context.state.config_dir);
+    let managed = prefs_data.effective_prefs(&profile.id);
+    let current = read_current_prefs(&profile.full_path(&context.state.config))
+        .into_iter()
+        .filter(|(name, _)| managed.contains_key(name))
+        .collect();
+
+    NativeResponse::success(NativeResponseData::Prefs {
+        profile: prefs_data.profiles.get(&profile.id).cloned().unwrap_or_default(),
+        pending: prefs_data.pending.contains(&profile.id),
+        global: prefs_data.global,
+        current
+    })
+}
+
+pub fn process_cmd_set_pref(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageSetPref) -> NativeResponse {
+    if let Err(e) = validate_pref(&msg.name, &msg.value) {
+        return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Invalid preference name or value!", e.to_string())
+    }
+
+    let NativeMessageSetPref { profile_id, name, value } = msg;
+    update_prefs(context, &profiles, profile_id.as_deref(), |prefs_data, profile_id| {
+        let prefs = match profile_id {
+            Some(id) => prefs_data.profiles.entry(id.to_owned()).or_default(),
+            None => &mut prefs_data.global
+        };
+        prefs.insert(name, value);
+    })
+}
+
+pub fn process_cmd_remove_pref(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageRemovePref) -> NativeResponse {
+    update_prefs(context, &profiles, msg.profile_id.as_deref(), |prefs_data, profile_id| {
+        match profile_id {
+            Some(id) => if let Some(prefs) = prefs_data.profiles.get_mut(id) {
+                prefs.remove(&msg.name);
+                if prefs.is_empty() {
+                    prefs_data.profiles.remove(id);
+                }
+            },
+            None => { prefs_data.global.remove(&msg.name); }
+        }
+    })
+}
+
+// Change the prefs of a profile (or the global prefs if no profile is specified) and write them into
+// the user.js of every affected profile
+fn update_prefs<F>(context: &AppContext, profiles: &ProfilesIniState, profile_id: Option<&str>, update: F) -> NativeResponse
+    where F: FnOnce(&mut PrefsData, Option<&str>) {
+    if let Some(profile_id) = profile_id {
+        if !profiles.profile_entries.iter().any(|p| p.id == profile_id) {
+            return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+        }
+    }
+
+    let config = &context.state.config;
+    let mut prefs_data = PrefsData::read(&context.state.config_dir);
+    update(&mut prefs_data, profile_id);
+
+    let mut results = HashMap::new();
+    let mut apply_error = None;
+    for profile in profiles.profile_entries.iter().filter(|p| profile_id.is_none_or(|id| p.id == id)) {
+        match prefs_data.apply(&profile.id, &profile.full_path(config), config) {
+            Ok(result) => { results.insert(profile.id.clone(), result); }
+            Err(e) => {
+                apply_error = Some(e);
+                break
+            }
+        }
+    }
+
+    // Keep the store in sync with the profiles that were already written even if a profile failed
+    if let Err(e) = prefs_data.write(&context.state.config_dir, profiles) {
+        return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to save preferences!", e.to_string())
+    }
+    if let Some(e) = apply_error {
+        return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to write preferences into profile!", e.to_string())
+    }
+
+    NativeResponse::success(NativeResponseData::PrefsUpdated { results })
+}
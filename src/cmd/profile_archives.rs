@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+use crate::AppContext;
+use crate::cmd::create_profile::{check_name_conflict, notify_new_profile, register_new_profile};
+use crate::cmd::{check_profile_closed, lock_stores};
+use crate::native_req::{NativeMessageExportProfile, NativeMessageImportProfile};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::profile_archive::{discard_imported_profile, export_profile, import_profile, read_archive_manifest, ExportProfileError};
+use crate::profiles::{ProfileEntry, ProfilesIniState};
+use crate::storage::custom_avatars_path;
+
+pub fn process_cmd_export_profile(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageExportProfile) -> NativeResponse {
+    let profile = match profiles.profile_entries.iter().find(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+
+    let profile_path = profile.full_path(&context.state.config);
+
+    if let Err(e) = check_profile_closed(&profile_path, &context.state.config, "exported") {
+        return e;
+    }
+
+    let target = match msg.path {
+        Some(p) => PathBuf::from(p),
+        None => match context.windowing.open_archive_save_picker(&profile.name) {
+            Some(p) => p,
+            None => return NativeResponse::error(NativeErrorCode::NoFileSelected, "No file was selected.")
+        }
+    };
+
+    log::trace!("Exporting profile {:?} to {:?}", profile_path, target);
+    match export_profile(profile, &profile_path, &custom_avatars_path(&context.state.data_dir), &target) {
+        Err(ExportProfileError::TargetExists) => return NativeResponse::error(
+            NativeErrorCode::ArchiveTargetExists,
+            "A file already exists in this location, please choose another location."
+        ),
+        Err(e) => return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to export profile!", e.to_string()),
+        Ok(()) => {}
+    }
+
+    NativeResponse::success(NativeResponseData::ProfileExported {
+        path: target.to_string_lossy().into_owned()
+    })
+}
+
+// The stores are only locked to check the name and once the archive is unpacked as unpacking may
+// take a while, the name is checked again once the profile is registered
+pub fn process_cmd_import_profile(context: &AppContext, msg: NativeMessageImportProfile) -> NativeResponse {
+    let archive = match msg.path {
+        Some(p) => PathBuf::from(p),
+        None => match context.windowing.open_archive_picker() {
+            Some(p) => p,
+            None => return NativeResponse::error(NativeErrorCode::NoFileSelected, "No file was selected.")
+        }
+    };
+
+    // Do not unpack an archive whose profile cannot be registered anyway
+    let name = match msg.name {
+        Some(name) => name,
+        None => match read_archive_manifest(&archive) {
+            Ok(manifest) => manifest.name,
+            Err(e) => return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to import profile!", e.to_string())
+        }
+    };
+    let name = name.trim().to_owned();
+    if let Err(e) = lock_stores(context.state).and_then(|(_store_lock, profiles)| check_name_conflict(&profiles, &name)) {
+        return e;
+    }
+
+    // The avatar and options are filled in from the archive once it is unpacked
+    let mut new_profile = ProfileEntry::new_relative(&name, None, Default::default());
+    let new_profile_full_path = new_profile.full_path(&context.state.config);
+
+    log::trace!("Importing profile {:?} into {:?}", archive, new_profile_full_path);
+    let imported = match import_profile(&archive, &new_profile_full_path, &custom_avatars_path(&context.state.data_dir)) {
+        Ok(i) => i,
+        Err(e) => return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to import profile!", e.to_string())
+    };
+    new_profile.avatar = imported.avatar.clone();
+    new_profile.options = imported.options.clone();
+
+    let result = lock_stores(context.state).and_then(|(_store_lock, mut profiles)| {
+        check_name_conflict(&profiles, &new_profile.name)?;
+        let resp = register_new_profile(context.state, &mut profiles, new_profile)?;
+        notify_new_profile(context, &profiles);
+        Ok(resp)
+    });
+
+    match result {
+        Ok(resp) => NativeResponse::success(NativeResponseData::ProfileImported { profile: resp }),
+        Err(e) => {
+            discard_imported_profile(&imported, &new_profile_full_path);
+            e
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::AppContext;
+use crate::cmd::check_expected_revision;
+use crate::cmd::create_profile::{check_name_conflict, notify_new_profile, register_new_profile};
+use crate::ipc::notify_profile_changed;
+use crate::native_req::{NativeMessageAdoptProfile, NativeMessageRemoveDanglingEntry};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::profile_scan::scan_profiles;
+use crate::profiles::{ProfileEntry, ProfilesIniState, write_profiles};
+use crate::profiles_order::OrderData;
+
+pub fn process_cmd_scan_profiles(context: &AppContext, profiles: ProfilesIniState) -> NativeResponse {
+    NativeResponse::success(NativeResponseData::ProfileScan {
+        scan: scan_profiles(&context.state.config, &profiles)
+    })
+}
+
+pub fn process_cmd_adopt_profile(context: &AppContext, mut profiles: ProfilesIniState, msg: NativeMessageAdoptProfile) -> NativeResponse {
+    // Only adopt folders that we reported to avoid adding arbitrary paths
+    let scan = scan_profiles(&context.state.config, &profiles);
+    let orphan = match scan.orphans.into_iter().find(|o| o.path == msg.path) {
+        Some(o) => o,
+        None => return NativeResponse::error(NativeErrorCode::OrphanNotFound, "This folder is not an orphaned profile, please scan again.")
+    };
+
+    let name = msg.name.unwrap_or(orphan.suggested_name);
+    if let Err(e) = check_name_conflict(&profiles, &name) {
+        return e;
+    }
+
+    let new_profile = ProfileEntry::new(&name, orphan.path, true, msg.avatar, msg.options.unwrap_or_default());
+
+    match register_new_profile(context.state, &mut profiles, new_profile) {
+        Ok(resp) => {
+            notify_new_profile(context, &profiles);
+            NativeResponse::success(NativeResponseData::ProfileAdopted { profile: resp })
+        },
+        Err(e) => e
+    }
+}
+
+pub fn process_cmd_remove_dangling_entry(context: &AppContext, mut profiles: ProfilesIniState, msg: NativeMessageRemoveDanglingEntry) -> NativeResponse {
+    let profile_index = match profiles.profile_entries.iter().position(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+
+    if let Err(e) = check_expected_revision(msg.expected_revision, profiles.profile_entries[profile_index].revision) {
+        return e;
+    }
+
+    // Profiles that still have a folder must be deleted instead
+    if profiles.profile_entries[profile_index].full_path(&context.state.config).exists() {
+        return NativeResponse::error(NativeErrorCode::ProfileNotDangling, "The folder of this profile still exists, please scan again.")
+    }
+
+    let profile = profiles.profile_entries.remove(profile_index);
+
+    // Make another profile the default
+    if profile.default {
+        if let Some(new_def_profile) = profiles.profile_entries.first_mut() {
+            new_def_profile.default = true;
+            new_def_profile.revision += 1;
+        }
+    }
+
+    // Re-calculate profile order
+    OrderData::try_rewrite(context, &profiles);
+
+    // Write new profile list, this also drops the avatar and options of the profile
+    if let Err(e) = write_profiles(context.state, &profiles) {
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
+    }
+    notify_profile_changed(context, &profiles);
+
+    NativeResponse::success(NativeResponseData::DanglingEntryRemoved)
+}
@@ -0,0 +1,26 @@
+use crate::AppContext;
+use crate::profiles::{ProfilesIniState, write_profiles};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::ipc::notify_profile_changed;
+use crate::profiles_order::OrderData;
+
+pub fn process_cmd_repair_profiles_ini(context: &AppContext, mut profiles: ProfilesIniState) -> NativeResponse {
+    let repairs = profiles.repair(&context.state.config);
+
+    if !repairs.is_empty() {
+        log::info!("Repaired profiles.ini: {:?}", repairs);
+
+        // Restored sections add profiles to the list
+        OrderData::try_rewrite(context, &profiles);
+
+        if let Err(e) = write_profiles(context.state, &profiles) {
+            return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
+        }
+        notify_profile_changed(context, &profiles);
+    }
+
+    NativeResponse::success(NativeResponseData::ProfilesIniRepaired {
+        repairs,
+        warnings: profiles.warnings(&context.state.config)
+    })
+}
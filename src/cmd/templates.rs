@@ -0,0 +1,34 @@
+use crate::AppContext;
+use crate::native_req::{NativeMessageDeleteTemplate, NativeMessageSaveProfileAsTemplate};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::profiles::ProfilesIniState;
+use crate::templates::{delete_template, list_templates, save_template};
+
+pub fn process_cmd_list_templates(context: &AppContext) -> NativeResponse {
+    NativeResponse::success(NativeResponseData::Templates {
+        templates: list_templates(&context.state.data_dir)
+    })
+}
+
+pub fn process_cmd_save_profile_as_template(context: &AppContext, profiles: ProfilesIniState, msg: NativeMessageSaveProfileAsTemplate) -> NativeResponse {
+    let profile = match profiles.profile_entries.iter().find(|p| p.id == msg.profile_id) {
+        Some(p) => p,
+        None => return NativeResponse::error(NativeErrorCode::ProfileNotFound, "No profile with the specified id could be found!")
+    };
+
+    if msg.name.trim().is_empty() {
+        return NativeResponse::error(NativeErrorCode::InvalidMessage, "Please enter a name for the template.")
+    }
+
+    match save_template(&context.state.data_dir, &msg.name, &profile.full_path(&context.state.config), &msg.extension_ids) {
+        Ok(template) => NativeResponse::success(NativeResponseData::TemplateSaved { template }),
+        Err(e) => NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to save profile as template!", e.to_string())
+    }
+}
+
+pub fn process_cmd_delete_template(context: &AppContext, msg: NativeMessageDeleteTemplate) -> NativeResponse {
+    match delete_template(&context.state.data_dir, &msg.template_id) {
+        Ok(()) => NativeResponse::success(NativeResponseData::TemplateDeleted),
+        Err(e) => NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to delete template!", e.to_string())
+    }
+}
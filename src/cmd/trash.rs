@@ -0,0 +1,73 @@
+use crate::AppContext;
+use crate::cmd::create_profile::check_name_conflict;
+use crate::ipc::notify_profile_changed;
+use crate::native_req::{NativeMessagePurgeTrash, NativeMessageRestoreProfile};
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData, NativeResponseProfileListProfileEntry};
+use crate::profiles::{ProfileEntry, ProfilesIniState, write_profiles};
+use crate::profiles_order::OrderData;
+use crate::trash::{list_trash, purge_all_trash, purge_trash_entry, read_trash_entry, return_to_trash, take_from_trash};
+
+pub fn process_cmd_list_trash(context: &AppContext) -> NativeResponse {
+    NativeResponse::success(NativeResponseData::Trash {
+        entries: list_trash(&context.state.data_dir)
+    })
+}
+
+pub fn process_cmd_restore_profile(context: &AppContext, mut profiles: ProfilesIniState, msg: NativeMessageRestoreProfile) -> NativeResponse {
+    let data_dir = &context.state.data_dir;
+    let entry = match read_trash_entry(data_dir, &msg.trash_id) {
+        Ok(e) => e,
+        Err(e) => return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to load profile from the trash.", e.to_string())
+    };
+
+    let name = msg.name.unwrap_or(entry.name);
+    if let Err(e) = check_name_conflict(&profiles, &name) {
+        return e;
+    }
+
+    // Restore the profile to its original folder unless something took its place, the profile
+    // keeps its id as it is stored in the profile folder
+    let mut new_profile = ProfileEntry::new(&name, entry.path, entry.is_relative, entry.avatar, entry.options);
+    let original_full_path = new_profile.full_path(&context.state.config);
+    if profiles.profile_entries.iter().any(|p| p.full_path(&context.state.config) == original_full_path)
+        || original_full_path.exists() {
+        new_profile = ProfileEntry::new_relative(&name, new_profile.avatar, new_profile.options);
+    }
+
+    let new_profile_full_path = new_profile.full_path(&context.state.config);
+    let entry = match take_from_trash(data_dir, &msg.trash_id, &new_profile_full_path) {
+        Ok(e) => e,
+        Err(e) => return NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to restore profile from the trash!", e.to_string())
+    };
+
+    profiles.assign_profile_id(&mut new_profile, &context.state.config);
+    let resp = NativeResponseProfileListProfileEntry::from_profile_entry(&new_profile);
+    let new_profile_id = new_profile.id.clone();
+    profiles.profile_entries.push(new_profile);
+
+    // Keep the profile in the trash if it cannot be added to the profile list
+    if let Err(e) = write_profiles(context.state, &profiles) {
+        if let Err(e) = return_to_trash(data_dir, &entry, &new_profile_full_path) {
+            log::error!("Failed to move profile {:?} back into the trash: {}", new_profile_full_path, e);
+        }
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
+    }
+
+    // Put the profile back where it was in the profile order
+    OrderData::try_rewrite_placing(context, &profiles, entry.order_position.map(|p| (new_profile_id.as_str(), p)));
+    notify_profile_changed(context, &profiles);
+
+    NativeResponse::success(NativeResponseData::ProfileRestored { profile: resp })
+}
+
+pub fn process_cmd_purge_trash(context: &AppContext, msg: NativeMessagePurgeTrash) -> NativeResponse {
+    let result = match &msg.trash_id {
+        Some(id) => purge_trash_entry(&context.state.data_dir, id),
+        None => purge_all_trash(&context.state.data_dir)
+    };
+
+    match result {
+        Ok(()) => NativeResponse::success(NativeResponseData::TrashPurged),
+        Err(e) => NativeResponse::error_with_dbg_str(NativeErrorCode::from(&e), "Failed to empty the trash!", e.to_string())
+    }
+}
@@ -1,8 +1,8 @@
 use crate::AppContext;
 use crate::profiles::ProfilesIniState;
 use crate::native_req::NativeMessageUpdateOptions;
-use crate::native_resp::{NativeResponse, NativeResponseData};
-use crate::storage::global_options_data_path;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
+use crate::storage::{bump_state_revision, global_options_data_path};
 use crate::options::{read_global_options, write_global_options};
 use crate::ipc::notify_options_changed;
 
@@ -17,8 +17,9 @@ pub fn process_cmd_update_options(context: &AppContext,
     }
 
     if let Err(e) = write_global_options(&options_data_path, &options) {
-        return NativeResponse::error_with_dbg_msg("Failed to save new changes!", e);
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::from(&e), "Failed to save new changes!", e);
     }
+    bump_state_revision(&context.state.data_dir);
     notify_options_changed(context, &profiles);
 
     return NativeResponse::success(NativeResponseData::OptionsUpdated { options })
@@ -1,29 +1,43 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
+use std::path::Path;
+use crate::cmd::check_expected_revision;
 use crate::ipc::notify_update_profile_order;
-use crate::native_req::{NativeMessageLaunchProfile, NativeMessageUpdateProfileOrder};
-use crate::native_resp::{NativeResponse, NativeResponseData};
+use crate::native_req::NativeMessageUpdateProfileOrder;
+use crate::native_resp::{NativeErrorCode, NativeResponse, NativeResponseData};
 use crate::profiles::ProfilesIniState;
-use crate::profiles_order::{native_notify_updated_profile_order, OrderData};
+use crate::profiles_order::OrderData;
 use crate::state::AppContext;
 
 pub fn process_cmd_update_profiles_order(context: &AppContext,
                                          profiles: ProfilesIniState,
                                          msg: NativeMessageUpdateProfileOrder) -> NativeResponse {
-    let new_order_data = OrderData { order: msg.order };
+    let new_order_data = match build_profile_order(&context.state.config_dir, &profiles, msg) {
+        Ok(o) => o,
+        Err(e) => return e
+    };
+    if let Err(e) = new_order_data.write(context.state) {
+        return NativeResponse::error_with_dbg_msg(NativeErrorCode::StoreWriteFailed, "Could not save profile order.", e);
+    }
+
+    notify_update_profile_order(context, &profiles);
+
+    NativeResponse::success(NativeResponseData::ProfileOrderUpdated)
+}
+
+/// Validate the new order against the profile list without writing it
+pub fn build_profile_order(config_dir: &Path, profiles: &ProfilesIniState, msg: NativeMessageUpdateProfileOrder) -> Result<OrderData, NativeResponse> {
+    let cur_revision = OrderData::read(config_dir).revision;
+    check_expected_revision(msg.expected_revision, cur_revision)?;
+
+    let new_order_data = OrderData { order: msg.order, revision: cur_revision + 1 };
     let mut profile_map: HashSet<&str> = profiles.profile_entries.iter()
         .map(|x| x.id.as_str())
         .collect();
     for profile_id in &new_order_data.order {
         // Will also catch dupes if we remove them as we go
         if !profile_map.remove(profile_id.as_str()) {
-            return NativeResponse::error("Attempted to re-arrange profile that does not exist!");
+            return Err(NativeResponse::error(NativeErrorCode::ProfileNotFound, "Attempted to re-arrange profile that does not exist!"));
         }
     }
-    if let Err(e) = new_order_data.write(&context.state.config_dir) {
-        return NativeResponse::error_with_dbg_msg("Could not save profile order.", e);
-    }
-
-    notify_update_profile_order(context, &profiles);
-
-    NativeResponse::success(NativeResponseData::ProfileOrderUpdated)
+    Ok(new_order_data)
 }
@@ -6,10 +6,16 @@ use cfg_if::cfg_if;
 use std::fs::OpenOptions;
 use once_cell::sync::Lazy;
 
+const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
     browser_profile_dir: Option<PathBuf>,
-    browser_binary: Option<PathBuf>
+    browser_binary: Option<PathBuf>,
+    // Days after which deleted profiles are purged from the trash, 0 keeps them forever
+    trash_retention_days: Option<u64>,
+    // Install the current switcher extension into other profiles whenever the connector starts
+    sync_switcher_extension: Option<bool>
 }
 
 impl Config {
@@ -20,6 +26,12 @@ impl Config {
     pub fn browser_binary(&self) -> Option<&PathBuf> {
         self.browser_binary.as_ref()
     }
+    pub fn trash_retention_days(&self) -> u64 {
+        self.trash_retention_days.unwrap_or(DEFAULT_TRASH_RETENTION_DAYS)
+    }
+    pub fn sync_switcher_extension(&self) -> bool {
+        self.sync_switcher_extension.unwrap_or(false)
+    }
 
     pub fn profiles_ini_path(&self) -> PathBuf {
         let mut profiles_ini = self.browser_profile_dir();
@@ -118,7 +130,9 @@ impl Default for Config {
     fn default() -> Self {
         Config {
             browser_profile_dir: None,
-            browser_binary: None
+            browser_binary: None,
+            trash_retention_days: None,
+            sync_switcher_extension: None
         }
     }
 }
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use fs2::FileExt;
+use ulid::Ulid;
+use crate::ipc::notify_profile_changed_detached;
+use crate::locking::{StoreLock, StoreLockError};
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
+use crate::profiles::{read_profiles, write_profiles, ProfileEntry, ProfilesIniState, ReadProfilesError, WriteProfilesError};
+use crate::profiles_order::OrderData;
+use crate::state::AppState;
+
+// === EPHEMERAL PROFILES ===
+
+// Ephemeral profiles are created for a single browser session. A supervisor (the connector started
+// with `supervise-ephemeral`) waits for the browser to exit and then deletes the profile. The
+// profile folder contains a marker file so that profiles left behind by a crash can be cleaned up
+// later, the supervisor keeps the marker locked while it is running.
+pub const EPHEMERAL_MARKER_FILENAME: &str = ".profile-switcher-ephemeral";
+const EPHEMERAL_PATH_PREFIX: &str = "ephemeral-";
+
+// How long the browser may take to start using the profile
+const BROWSER_STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum EphemeralProfileError {
+    ProfileNotFound,
+    NotEphemeral,
+    ProfileInUse,
+    StoreLockError(StoreLockError),
+    ReadProfilesError(ReadProfilesError),
+    WriteProfilesError(WriteProfilesError),
+    DeleteDirError(io::Error)
+}
+
+impl fmt::Display for EphemeralProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EphemeralProfileError::ProfileNotFound => write!(f, "the profile no longer exists"),
+            EphemeralProfileError::NotEphemeral => write!(f, "the profile is not ephemeral"),
+            EphemeralProfileError::ProfileInUse => write!(f, "the profile is still in use"),
+            EphemeralProfileError::StoreLockError(e) => write!(f, "{}", e),
+            EphemeralProfileError::ReadProfilesError(e) => write!(f, "failed to read the profile list: {:?}", e),
+            EphemeralProfileError::WriteProfilesError(e) => write!(f, "failed to write the profile list: {:?}", e),
+            EphemeralProfileError::DeleteDirError(e) => write!(f, "failed to delete the profile folder: {}", e)
+        }
+    }
+}
+
+pub fn is_ephemeral_profile(profile_dir: &Path) -> bool {
+    profile_dir.join(EPHEMERAL_MARKER_FILENAME).is_file()
+}
+
+/// Build a new ephemeral profile stored in a new folder in the browser profile dir, the folder
+/// is not created
+pub fn new_ephemeral_profile(profiles: &ProfilesIniState) -> ProfileEntry {
+    // The browser is launched with the profile name so it must be unique
+    let name = (1..)
+        .map(|n| format!("Temporary profile {}", n))
+        .find(|n| !profiles.profile_entries.iter().any(|p| p.name.trim().eq_ignore_ascii_case(n)))
+        .unwrap();
+    let path = EPHEMERAL_PATH_PREFIX.to_owned() + &Ulid::new().to_string();
+    ProfileEntry::new(&name, path, true, None, Default::default())
+}
+
+pub fn mark_ephemeral(profile_dir: &Path) -> io::Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(profile_dir.join(EPHEMERAL_MARKER_FILENAME))
+        .map(|_| ())
+}
+
+/// Delete an ephemeral profile and its folder and save the profile list, the store lock must be held
+pub fn remove_ephemeral_profile(app_state: &AppState, profiles: &mut ProfilesIniState, profile_id: &str) -> Result<(), EphemeralProfileError> {
+    let profile_index = profiles.profile_entries.iter()
+        .position(|p| p.id == profile_id)
+        .ok_or(EphemeralProfileError::ProfileNotFound)?;
+    let profile_dir = profiles.profile_entries[profile_index].full_path(&app_state.config);
+    // Never delete a regular profile, even if we are told to
+    if profile_dir.exists() && !is_ephemeral_profile(&profile_dir) {
+        return Err(EphemeralProfileError::NotEphemeral)
+    }
+
+    log::trace!("Removing ephemeral profile: {:?}", profile_dir);
+    if profile_dir.exists() {
+        fs::remove_dir_all(&profile_dir).map_err(EphemeralProfileError::DeleteDirError)?;
+    }
+
+    let profile = profiles.profile_entries.remove(profile_index);
+    if profile.default {
+        if let Some(new_def_profile) = profiles.profile_entries.first_mut() {
+            new_def_profile.default = true;
+            new_def_profile.revision += 1;
+        }
+    }
+
+    let mut order_data = OrderData::read(&app_state.config_dir);
+    order_data.recalculate(profiles);
+    order_data.revision += 1;
+    if let Err(e) = order_data.write(app_state) {
+        log::error!("Failed to update profiles order: {:?}", e);
+    }
+
+    write_profiles(app_state, profiles).map_err(EphemeralProfileError::WriteProfilesError)
+}
+
+/// Wait for the browser to start and then exit, then delete the ephemeral profile
+pub fn supervise_ephemeral_profile(app_state: &AppState, profile_id: &str) -> Result<(), EphemeralProfileError> {
+    let profile_dir = read_profiles(&app_state.config, &app_state.config_dir)
+        .map_err(EphemeralProfileError::ReadProfilesError)?
+        .profile_entries.iter()
+        .find(|p| p.id == profile_id)
+        .ok_or(EphemeralProfileError::ProfileNotFound)?
+        .full_path(&app_state.config);
+    if !is_ephemeral_profile(&profile_dir) {
+        return Err(EphemeralProfileError::NotEphemeral)
+    }
+
+    // Tell `collect_ephemeral_profiles` that the profile is still supervised
+    let supervisor_lock = lock_marker(&profile_dir);
+
+    let start = Instant::now();
+    while !is_running(&profile_dir, app_state) && start.elapsed() < BROWSER_STARTUP_TIMEOUT {
+        thread::sleep(POLL_INTERVAL);
+    }
+    while is_running(&profile_dir, app_state) {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // The folder cannot be deleted while the marker is open on Windows
+    drop(supervisor_lock);
+
+    fs::create_dir_all(&app_state.data_dir).map_err(EphemeralProfileError::DeleteDirError)?;
+    let _store_lock = StoreLock::acquire(&app_state.data_dir)
+        .map_err(EphemeralProfileError::StoreLockError)?;
+    let mut profiles = read_profiles(&app_state.config, &app_state.config_dir)
+        .map_err(EphemeralProfileError::ReadProfilesError)?;
+    // The browser may have been started again in the meantime
+    if is_running(&profile_dir, app_state) {
+        return Err(EphemeralProfileError::ProfileInUse)
+    }
+    remove_ephemeral_profile(app_state, &mut profiles, profile_id)?;
+    notify_profile_changed_detached(&profiles);
+    Ok(())
+}
+
+/// Delete ephemeral profiles (and their folders) that were left behind because the browser or
+/// their supervisor crashed, the store lock must be held
+pub fn collect_ephemeral_profiles(app_state: &AppState, profiles: &mut ProfilesIniState) {
+    let leftover_ids: Vec<String> = profiles.profile_entries.iter()
+        .filter(|p| is_leftover(&p.full_path(&app_state.config), app_state))
+        .map(|p| p.id.clone())
+        .collect();
+    for profile_id in leftover_ids {
+        log::info!("Removing leftover ephemeral profile: {}", profile_id);
+        if let Err(e) = remove_ephemeral_profile(app_state, profiles, &profile_id) {
+            log::error!("Failed to remove leftover ephemeral profile {}: {}", profile_id, e);
+        }
+    }
+
+    // Folders of ephemeral profiles that were removed from profiles.ini but could not be deleted
+    let browser_profile_dir = app_state.config.browser_profile_dir();
+    let entries = match fs::read_dir(&browser_profile_dir) {
+        Ok(r) => r,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let is_known = profiles.profile_entries.iter()
+            .any(|p| p.full_path(&app_state.config) == entry.path());
+        let name = entry.file_name();
+        if !is_known && name.to_string_lossy().starts_with(EPHEMERAL_PATH_PREFIX) && is_leftover(&entry.path(), app_state) {
+            log::info!("Removing leftover ephemeral profile folder: {:?}", entry.path());
+            if let Err(e) = fs::remove_dir_all(entry.path()) {
+                log::error!("Failed to remove leftover ephemeral profile folder {:?}: {:?}", entry.path(), e);
+            }
+        }
+    }
+}
+
+fn is_running(profile_dir: &Path, app_state: &AppState) -> bool {
+    detect_profile_lock_state(profile_dir, &app_state.config) == ProfileLockState::Running
+}
+
+fn is_leftover(profile_dir: &Path, app_state: &AppState) -> bool {
+    if !is_ephemeral_profile(profile_dir) || is_running(profile_dir, app_state) {
+        return false
+    }
+    // Profiles that were just created may not have their supervisor yet
+    let marker = profile_dir.join(EPHEMERAL_MARKER_FILENAME);
+    let is_new = fs::metadata(&marker)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .is_none_or(|age| age < BROWSER_STARTUP_TIMEOUT);
+    !is_new && lock_marker(profile_dir).is_some()
+}
+
+fn lock_marker(profile_dir: &Path) -> Option<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(profile_dir.join(EPHEMERAL_MARKER_FILENAME))
+        .ok()?;
+    file.try_lock_exclusive().ok()?;
+    Some(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn supervised_profiles_are_not_collected() {
+        let profile_dir = TempDir::new("ephemeral-test");
+        assert!(!is_ephemeral_profile(&profile_dir));
+        mark_ephemeral(&profile_dir).unwrap();
+        assert!(is_ephemeral_profile(&profile_dir));
+
+        let supervisor_lock = lock_marker(&profile_dir);
+        assert!(supervisor_lock.is_some());
+        assert!(lock_marker(&profile_dir).is_none());
+        drop(supervisor_lock);
+        assert!(lock_marker(&profile_dir).is_some());
+    }
+}
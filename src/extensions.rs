@@ -0,0 +1,368 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::config::Config;
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
+use crate::profiles::ProfilesIniState;
+use crate::state::AppState;
+use crate::storage::write_file_atomic;
+
+// === EXTENSIONS ===
+
+// The browser keeps track of the addons installed in a profile in its extensions.json. Every addon
+// has a chunk in the `addons` array, the XPIs of addons installed by the user are stored in the
+// `extensions` folder of the profile and the chunk points at them with `path` and `rootURI`.
+pub const EXTENSIONS_JSON_FILENAME: &str = "extensions.json";
+const EXTENSIONS_DIRNAME: &str = "extensions";
+// Location of the addons installed by the user, the other locations are managed by the browser
+const PROFILE_ADDON_LOCATION: &str = "app-profile";
+
+pub type AddonChunk = Map<String, Value>;
+
+#[derive(Debug)]
+pub enum ExtensionsError {
+    /// The addon is not installed in the profile or cannot be copied (e.g. builtin addons)
+    ExtensionNotFound(String),
+    /// The connector has not been initialized so the switcher extension is unknown
+    SwitcherUnknown,
+    ReadExtensionsJsonError(io::Error),
+    BadExtensionsJson(serde_json::Error),
+    WriteExtensionsJsonError(io::Error),
+    SerializeExtensionsJsonError(serde_json::Error),
+    CopyXpiError(io::Error)
+}
+
+impl fmt::Display for ExtensionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtensionsError::ExtensionNotFound(id) => write!(f, "extension {} cannot be copied from the profile", id),
+            ExtensionsError::SwitcherUnknown => write!(f, "the switcher extension is not known yet"),
+            ExtensionsError::ReadExtensionsJsonError(e) => write!(f, "failed to read extensions.json: {}", e),
+            ExtensionsError::BadExtensionsJson(e) => write!(f, "extensions.json is invalid: {}", e),
+            ExtensionsError::WriteExtensionsJsonError(e) => write!(f, "failed to write extensions.json: {}", e),
+            ExtensionsError::SerializeExtensionsJsonError(e) => write!(f, "failed to serialize extensions.json: {}", e),
+            ExtensionsError::CopyXpiError(e) => write!(f, "failed to copy the extension file: {}", e)
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExtensionInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// `extension`, `theme`, `dictionary` or `locale`
+    #[serde(rename = "type")]
+    pub addon_type: Option<String>,
+    pub active: bool,
+    /// Whether the addon can be copied into other profiles, addons managed by the browser cannot
+    pub copyable: bool
+}
+
+/// Outcome of syncing an addon into a profile
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddonSyncResult {
+    /// The profile did not have the addon
+    Installed,
+    /// The profile had an older version of the addon
+    Updated,
+    UpToDate,
+    /// The profile needs the addon but is running, the browser would overwrite extensions.json
+    ProfileRunning,
+    Failed
+}
+
+#[derive(Serialize)]
+struct ExtensionsJson {
+    #[serde(rename = "schemaVersion")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema_version: Option<Value>,
+    addons: Vec<Value>
+}
+
+pub fn read_extensions_json(profile_dir: &Path) -> Result<Value, ExtensionsError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(profile_dir.join(EXTENSIONS_JSON_FILENAME))
+        .map_err(ExtensionsError::ReadExtensionsJsonError)?;
+    serde_json::from_reader(file).map_err(ExtensionsError::BadExtensionsJson)
+}
+
+/// List the addons of a profile, a profile without extensions.json has no addons
+pub fn list_extensions(profile_dir: &Path) -> Result<Vec<ExtensionInfo>, ExtensionsError> {
+    let extensions_json = match read_extensions_json(profile_dir) {
+        Ok(j) => j,
+        Err(ExtensionsError::ReadExtensionsJsonError(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    Ok(addon_chunks(&extensions_json)
+        .filter_map(|chunk| Some(ExtensionInfo {
+            id: addon_id(chunk)?.to_owned(),
+            name: addon_name(chunk).map(str::to_owned),
+            version: addon_version(chunk).map(str::to_owned),
+            addon_type: chunk.get("type").and_then(Value::as_str).map(str::to_owned),
+            active: chunk.get("active").and_then(Value::as_bool).unwrap_or(false),
+            copyable: copyable_addon_xpi_path(chunk).is_some()
+        }))
+        .collect())
+}
+
+pub fn addon_chunks(extensions_json: &Value) -> impl Iterator<Item=&AddonChunk> {
+    extensions_json.get("addons")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object)
+}
+
+pub fn addon_id(chunk: &AddonChunk) -> Option<&str> {
+    chunk.get("id").and_then(Value::as_str)
+}
+
+pub fn find_addon_chunk<'a>(extensions_json: &'a Value, id: &str) -> Option<&'a AddonChunk> {
+    addon_chunks(extensions_json).find(|addon| addon_id(addon) == Some(id))
+}
+
+pub fn addon_version(chunk: &AddonChunk) -> Option<&str> {
+    chunk.get("version").and_then(Value::as_str)
+}
+
+pub fn addon_name(chunk: &AddonChunk) -> Option<&str> {
+    chunk.get("defaultLocale")
+        .and_then(|l| l.get("name"))
+        .and_then(Value::as_str)
+}
+
+/// Path of the XPI of an addon, builtin addons do not have one
+pub fn addon_xpi_path(chunk: &AddonChunk) -> Option<PathBuf> {
+    chunk.get("path").and_then(Value::as_str).map(PathBuf::from)
+}
+
+// Only addons installed by the user into the profile can be copied, the browser installs the others itself
+fn copyable_addon_xpi_path(chunk: &AddonChunk) -> Option<PathBuf> {
+    match chunk.get("location").and_then(Value::as_str) {
+        Some(location) if location != PROFILE_ADDON_LOCATION => None,
+        _ => addon_xpi_path(chunk).filter(|p| p.is_file())
+    }
+}
+
+/// Find the addons with the specified ids in an extensions.json along with the paths of their XPIs
+pub fn select_addons(extensions_json: &Value, ids: &[String]) -> Result<Vec<(AddonChunk, PathBuf)>, ExtensionsError> {
+    ids.iter()
+        .map(|id| find_addon_chunk(extensions_json, id)
+            .and_then(|chunk| Some((chunk.clone(), copyable_addon_xpi_path(chunk)?)))
+            .ok_or_else(|| ExtensionsError::ExtensionNotFound(id.clone())))
+        .collect()
+}
+
+/// Copy addons from one profile into another, the addons are merged into the extensions.json of
+/// the target profile
+pub fn copy_extensions(source_profile_dir: &Path, target_profile_dir: &Path, ids: &[String]) -> Result<(), ExtensionsError> {
+    let extensions_json = read_extensions_json(source_profile_dir)?;
+    let addons = select_addons(&extensions_json, ids)?;
+    install_addons(target_profile_dir, extensions_json.get("schemaVersion"), addons)?;
+    log::trace!("Copied extensions {:?} from profile {:?} to profile {:?}", ids, source_profile_dir, target_profile_dir);
+    Ok(())
+}
+
+/// Install addons into a profile: the XPI of every addon is copied into the profile and the addon
+/// is added to the profile's extensions.json (replacing the addon if it is already there).
+/// Each addon is passed with the path of its XPI, `schema_version` is only used if the profile has
+/// no extensions.json yet.
+pub fn install_addons(profile_dir: &Path,
+                      schema_version: Option<&Value>,
+                      addons: Vec<(AddonChunk, PathBuf)>) -> Result<(), ExtensionsError> {
+    let mut installed = Vec::new();
+    for (mut chunk, xpi_path) in addons {
+        let xpi_filename = match xpi_path.file_name() {
+            Some(f) => f,
+            None => continue
+        };
+        let new_xpi_path = profile_dir.join(EXTENSIONS_DIRNAME).join(xpi_filename);
+        fs::create_dir_all(profile_dir.join(EXTENSIONS_DIRNAME)).map_err(ExtensionsError::CopyXpiError)?;
+        fs::copy(&xpi_path, &new_xpi_path).map_err(ExtensionsError::CopyXpiError)?;
+        relocate_addon_chunk(&mut chunk, &new_xpi_path);
+        installed.push(chunk);
+    }
+
+    let (schema_version, mut addons) = match read_extensions_json(profile_dir) {
+        Ok(existing) => (
+            existing.get("schemaVersion").cloned(),
+            existing.get("addons").and_then(Value::as_array).cloned().unwrap_or_default()
+        ),
+        Err(ExtensionsError::ReadExtensionsJsonError(e)) if e.kind() == io::ErrorKind::NotFound => (schema_version.cloned(), Vec::new()),
+        Err(e) => return Err(e)
+    };
+    for chunk in installed {
+        if let Some(id) = addon_id(&chunk) {
+            addons.retain(|a| a.as_object().and_then(addon_id) != Some(id));
+        }
+        addons.push(Value::Object(chunk));
+    }
+
+    let serialized = serde_json::to_vec(&ExtensionsJson { schema_version, addons })
+        .map_err(ExtensionsError::SerializeExtensionsJsonError)?;
+    write_file_atomic(&profile_dir.join(EXTENSIONS_JSON_FILENAME), &serialized)
+        .map_err(ExtensionsError::WriteExtensionsJsonError)
+}
+
+/// Install the version of an addon from the source profile into every target profile that does
+/// not have it or has an older version. Profiles that are running are left alone.
+pub fn sync_addon<'a>(source_profile_dir: &Path,
+                      id: &str,
+                      targets: impl Iterator<Item=(&'a str, PathBuf)>,
+                      config: &Config) -> Result<HashMap<String, AddonSyncResult>, ExtensionsError> {
+    let source_json = read_extensions_json(source_profile_dir)?;
+    let (chunk, xpi_path) = select_addons(&source_json, &[id.to_owned()])?.remove(0);
+    let version = addon_version(&chunk).unwrap_or_default();
+
+    let mut results = HashMap::new();
+    for (profile_id, profile_dir) in targets.filter(|(_, dir)| dir != source_profile_dir) {
+        let needed = match read_extensions_json(&profile_dir) {
+            Ok(target_json) => match find_addon_chunk(&target_json, id) {
+                Some(target_chunk) => match compare_addon_versions(addon_version(target_chunk).unwrap_or_default(), version) {
+                    Ordering::Less => AddonSyncResult::Updated,
+                    _ => AddonSyncResult::UpToDate
+                },
+                None => AddonSyncResult::Installed
+            },
+            // Profiles that were never opened do not have an extensions.json yet
+            Err(ExtensionsError::ReadExtensionsJsonError(e)) if e.kind() == io::ErrorKind::NotFound => AddonSyncResult::Installed,
+            Err(e) => {
+                log::error!("Failed to read extensions of profile {}: {:?}", profile_id, e);
+                AddonSyncResult::Failed
+            }
+        };
+
+        let result = match needed {
+            AddonSyncResult::Installed | AddonSyncResult::Updated => {
+                if detect_profile_lock_state(&profile_dir, config) == ProfileLockState::Running {
+                    AddonSyncResult::ProfileRunning
+                } else if let Err(e) = install_addons(&profile_dir, source_json.get("schemaVersion"), vec![(chunk.clone(), xpi_path.clone())]) {
+                    log::error!("Failed to install addon {} into profile {}: {:?}", id, profile_id, e);
+                    AddonSyncResult::Failed
+                } else {
+                    needed
+                }
+            }
+            other => other
+        };
+        results.insert(profile_id.to_owned(), result);
+    }
+    log::trace!("Synced addon {} version {}: {:?}", id, version, results);
+    Ok(results)
+}
+
+/// Sync the switcher extension of the current profile into all other profiles
+pub fn sync_switcher_extension(app_state: &AppState, profiles: &ProfilesIniState) -> Result<HashMap<String, AddonSyncResult>, ExtensionsError> {
+    let extension_id = app_state.extension_id.as_ref().ok_or(ExtensionsError::SwitcherUnknown)?;
+    let cur_profile = profiles.profile_entries.iter()
+        .find(|p| Some(&p.id) == app_state.cur_profile_id.as_ref())
+        .ok_or(ExtensionsError::SwitcherUnknown)?;
+
+    sync_addon(
+        &cur_profile.full_path(&app_state.config),
+        extension_id,
+        profiles.profile_entries.iter().map(|p| (p.id.as_str(), p.full_path(&app_state.config))),
+        &app_state.config
+    )
+}
+
+// Addon versions are dot separated parts that start with a number (e.g. 1.2.3 or 1.0b2), numbers
+// are compared numerically and the rest of a part as a string. Missing parts count as 0.
+fn compare_addon_versions(a: &str, b: &str) -> Ordering {
+    let split_part = |part: &str| {
+        let digits = part.find(|c: char| !c.is_ascii_digit()).unwrap_or(part.len());
+        (part[..digits].parse::<u64>().unwrap_or(0), part[digits..].to_owned())
+    };
+    let a_parts: Vec<&str> = a.split('.').collect();
+    let b_parts: Vec<&str> = b.split('.').collect();
+    for i in 0..a_parts.len().max(b_parts.len()) {
+        let (a_num, a_rest) = split_part(a_parts.get(i).copied().unwrap_or("0"));
+        let (b_num, b_rest) = split_part(b_parts.get(i).copied().unwrap_or("0"));
+        // A pre-release suffix sorts before the plain version
+        let ordering = a_num.cmp(&b_num).then_with(|| match (a_rest.is_empty(), b_rest.is_empty()) {
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            _ => a_rest.cmp(&b_rest)
+        });
+        if ordering != Ordering::Equal {
+            return ordering
+        }
+    }
+    Ordering::Equal
+}
+
+// Point the chunk of an addon at a new XPI
+fn relocate_addon_chunk(chunk: &mut AddonChunk, new_xpi_path: &Path) {
+    chunk.insert("path".to_owned(), Value::String(new_xpi_path.to_string_lossy().to_string()));
+
+    if let Some(Value::String(_)) = chunk.get("rootURI") {
+        let mut new_root_uri = url::Url::parse("file://").unwrap();
+        new_root_uri.set_path(&new_xpi_path.to_string_lossy());
+        let mut new_root_uri: String = new_root_uri.into();
+        new_root_uri.insert_str(0, "jar:");
+        new_root_uri = new_root_uri.add("!/");
+        chunk.insert("rootURI".to_owned(), Value::String(new_root_uri));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn copy_extensions_merges_into_existing_profile() {
+        let root = TempDir::new("extensions-test");
+        let source_dir = root.join("source");
+        let target_dir = root.join("target");
+        fs::create_dir_all(source_dir.join(EXTENSIONS_DIRNAME)).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        let xpi_path = source_dir.join(EXTENSIONS_DIRNAME).join("a@example.com.xpi");
+        fs::write(&xpi_path, "xpi").unwrap();
+        fs::write(source_dir.join(EXTENSIONS_JSON_FILENAME), json!({
+            "schemaVersion": 35,
+            "addons": [
+                { "id": "a@example.com", "location": "app-profile", "path": xpi_path, "rootURI": "jar:file:///a.xpi!/" },
+                { "id": "builtin@example.com", "location": "app-builtin" }
+            ]
+        }).to_string()).unwrap();
+        fs::write(target_dir.join(EXTENSIONS_JSON_FILENAME), json!({
+            "schemaVersion": 35,
+            "addons": [{ "id": "b@example.com", "location": "app-profile" }]
+        }).to_string()).unwrap();
+
+        let listed = list_extensions(&source_dir).unwrap();
+        assert_eq!(listed.iter().filter(|e| e.copyable).count(), 1);
+        assert!(matches!(
+            copy_extensions(&source_dir, &target_dir, &["builtin@example.com".to_owned()]),
+            Err(ExtensionsError::ExtensionNotFound(_))
+        ));
+
+        copy_extensions(&source_dir, &target_dir, &["a@example.com".to_owned()]).unwrap();
+        let target_json = read_extensions_json(&target_dir).unwrap();
+        assert!(find_addon_chunk(&target_json, "b@example.com").is_some());
+        let chunk = find_addon_chunk(&target_json, "a@example.com").unwrap();
+        let new_xpi_path = target_dir.join(EXTENSIONS_DIRNAME).join("a@example.com.xpi");
+        assert_eq!(addon_xpi_path(chunk), Some(new_xpi_path.clone()));
+        assert!(chunk["rootURI"].as_str().unwrap().ends_with("target/extensions/a@example.com.xpi!/"));
+        assert!(new_xpi_path.is_file());
+    }
+
+    #[test]
+    fn addon_versions_compare_numerically() {
+        assert_eq!(compare_addon_versions("1.10.0", "1.9.2"), Ordering::Greater);
+        assert_eq!(compare_addon_versions("1.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare_addon_versions("2.0b1", "2.0"), Ordering::Less);
+        assert_eq!(compare_addon_versions("", "0.1"), Ordering::Less);
+    }
+}
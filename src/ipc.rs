@@ -102,7 +102,8 @@ fn handle_ipc_cmd(context: &AppContext, cmd: IPCCommand) {
                         // Notify updated profile list
                         write_native_event(NativeResponseEvent::ProfileList {
                             current_profile_id: pid.to_owned(),
-                            profiles: profiles.profile_entries.iter().map(NativeResponseProfileListProfileEntry::from_profile_entry).collect()
+                            profiles: profiles.profile_entries.iter().map(NativeResponseProfileListProfileEntry::from_profile_entry).collect(),
+                            warnings: profiles.warnings(&context.state.config)
                         });
                     }
                 },
@@ -170,27 +171,31 @@ fn send_ipc_cmd(context: &AppContext, target_profile_id: &str, cmd: IPCCommand)
         handle_ipc_cmd(context, cmd);
         Ok(())
     } else {
-        let socket_name = get_ipc_socket_name(target_profile_id, false)
-            .map_err(IpcError::IoError)?;
-
-        let conn = Socket::new(Protocol::Req0).map_err(IpcError::NetworkError)?;
-        conn.set_opt::<SendTimeout>(Some(Duration::from_millis(500)));
-        conn.set_opt::<RecvTimeout>(Some(Duration::from_millis(3000)));
-        conn.dial(&socket_name).map_err(IpcError::NetworkError)?;
-        log::trace!("Writing IPC command...");
-        let serialized = serde_cbor::to_vec(&cmd)
-            .map_err(IpcError::SerializationError)?;
-        conn.send(Message::from(&serialized));
-        log::trace!("IPC command written, reading status...");
-        let resp = conn.recv()
-            .map_err(IpcError::NetworkError)?;
-        let status = resp.first().unwrap_or(&1);
-        log::trace!("IPC command status is: {}", status);
-        if *status == 0 {
-            Ok(())
-        } else {
-            Err(IpcError::BadStatus)
-        }
+        dial_ipc_cmd(target_profile_id, cmd)
+    }
+}
+
+fn dial_ipc_cmd(target_profile_id: &str, cmd: IPCCommand) -> std::result::Result<(), IpcError> {
+    let socket_name = get_ipc_socket_name(target_profile_id, false)
+        .map_err(IpcError::IoError)?;
+
+    let conn = Socket::new(Protocol::Req0).map_err(IpcError::NetworkError)?;
+    conn.set_opt::<SendTimeout>(Some(Duration::from_millis(500)));
+    conn.set_opt::<RecvTimeout>(Some(Duration::from_millis(3000)));
+    conn.dial(&socket_name).map_err(IpcError::NetworkError)?;
+    log::trace!("Writing IPC command...");
+    let serialized = serde_cbor::to_vec(&cmd)
+        .map_err(IpcError::SerializationError)?;
+    conn.send(Message::from(&serialized));
+    log::trace!("IPC command written, reading status...");
+    let resp = conn.recv()
+        .map_err(IpcError::NetworkError)?;
+    let status = resp.first().unwrap_or(&1);
+    log::trace!("IPC command status is: {}", status);
+    if *status == 0 {
+        Ok(())
+    } else {
+        Err(IpcError::BadStatus)
     }
 }
 
@@ -208,6 +213,16 @@ pub fn notify_profile_changed(context: &AppContext, profiles: &ProfilesIniState)
     }
 }
 
+// Notify all running instances to update their profile list, for processes that do not talk to an extension
+pub fn notify_profile_changed_detached(profiles: &ProfilesIniState) {
+    for profile in &profiles.profile_entries {
+        // Profiles that are not running cannot be reached
+        if let Err(e) = dial_ipc_cmd(&profile.id, IPCCommand::UpdateProfileList) {
+            log::trace!("Failed to notify profile {} of updated profile list: {:?}", profile.id, e);
+        }
+    }
+}
+
 // Notify all running instances to update their options
 pub fn notify_options_changed(context: &AppContext, profiles: &ProfilesIniState) {
     for profile in &profiles.profile_entries {
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use fs2::FileExt;
+use once_cell::sync::Lazy;
+
+// === STORE LOCKING ===
+
+// How long to wait for other connectors to finish writing before giving up
+const STORE_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const STORE_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// File locks are not reliably exclusive between threads of the same process, so we also hold this.
+// It is not reentrant, a thread that already holds the store lock must not acquire it again.
+static THREAD_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Held while reading, modifying and writing profiles.ini, installs.ini and the connector's
+/// stores. Every connector instance (one per running profile) shares the same lock file.
+pub struct StoreLock {
+    file: File,
+    _thread_guard: MutexGuard<'static, ()>
+}
+
+#[derive(Debug)]
+pub enum StoreLockError {
+    OpenLockFileError(io::Error),
+    LockError(io::Error),
+    Timeout
+}
+
+impl fmt::Display for StoreLockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreLockError::OpenLockFileError(e) => write!(f, "failed to open the store lock file: {}", e),
+            StoreLockError::LockError(e) => write!(f, "failed to lock the store lock file: {}", e),
+            StoreLockError::Timeout => write!(f, "timed out waiting for the store lock")
+        }
+    }
+}
+
+impl StoreLock {
+    pub fn acquire(data_dir: &Path) -> Result<StoreLock, StoreLockError> {
+        // Both locks share the same deadline
+        let start = Instant::now();
+        let thread_guard = loop {
+            match THREAD_LOCK.try_lock() {
+                Ok(guard) => break guard,
+                Err(TryLockError::Poisoned(e)) => break e.into_inner(),
+                Err(TryLockError::WouldBlock) => wait_for_retry(start)?
+            }
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(data_dir.join("stores.lock"))
+            .map_err(StoreLockError::OpenLockFileError)?;
+
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(_) => break,
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => wait_for_retry(start)?,
+                Err(e) => return Err(StoreLockError::LockError(e))
+            }
+        }
+
+        log::trace!("Store lock acquired after {:?}", start.elapsed());
+
+        Ok(StoreLock {
+            file,
+            _thread_guard: thread_guard
+        })
+    }
+}
+
+fn wait_for_retry(start: Instant) -> Result<(), StoreLockError> {
+    if start.elapsed() >= STORE_LOCK_TIMEOUT {
+        return Err(StoreLockError::Timeout)
+    }
+    thread::sleep(STORE_LOCK_RETRY_INTERVAL);
+    Ok(())
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.unlock() {
+            log::error!("Failed to release store lock: {:?}", e);
+        }
+    }
+}
@@ -11,6 +11,22 @@ mod process;
 mod windowing;
 mod avatars;
 mod versions;
+mod locking;
+mod backups;
+mod profile_files;
+mod profile_archive;
+mod cli;
+mod trash;
+mod profile_lock;
+mod profile_scan;
+mod profile_identity;
+mod ephemeral;
+mod extensions;
+mod templates;
+mod prefs;
+mod profile_detection;
+#[cfg(test)]
+mod test_util;
 
 extern crate ini;
 extern crate serde;
@@ -49,10 +65,11 @@ use rand::Rng;
 use crate::avatars::update_and_native_notify_avatars;
 use crate::config::{read_configuration};
 use crate::state::{AppContext, AppState};
-use crate::native_resp::{NativeResponseEvent, write_native_response, NativeResponseWrapper, NativeResponse, write_native_event};
+use crate::native_resp::{NativeResponseEvent, write_native_response, NativeResponseWrapper, NativeResponse, write_native_event, NativeErrorCode};
 use crate::cmd::{execute_cmd_for_message, execute_init_cmd};
 use crate::ipc::setup_ipc;
-use crate::native_req::{read_incoming_message};
+use crate::cli::try_run_cli;
+use crate::native_req::{read_incoming_message, ReadMessageError};
 use crate::profiles_order::native_notify_updated_profile_order;
 use crate::windowing::Windowing;
 
@@ -95,16 +112,19 @@ fn main() {
     // Automatically enable backtraces
     env::set_var("RUST_BACKTRACE", "full");
 
+    // Handle commands from the user before speaking the native messaging protocol
+    let args: Vec<String> = env::args().collect();
+    if let Some(exit_code) = try_run_cli(&args) {
+        std::process::exit(exit_code);
+    }
+
     // Notify extension of our version
     write_native_event(NativeResponseEvent::ConnectorInformation {
         version: APP_VERSION.to_string()
     });
 
     // Calculate storage dirs
-    let project_dirs = ProjectDirs::from("ax.nd",
-                                        "nulldev",
-                                        "FirefoxProfileSwitcher")
-        .expect("Could not initialize configuration (failed to find storage dir)!");
+    let project_dirs = project_dirs();
     let pref_dir = project_dirs.preference_dir();
     let data_dir = project_dirs.data_local_dir();
 
@@ -160,7 +180,6 @@ fn main() {
     }
 
     // Find extension ID
-    let args: Vec<String> = env::args().collect();
     let extension_id = args.get(2);
     if extension_id.is_none() {
         log::warn!("Could not determine extension ID!");
@@ -194,9 +213,8 @@ fn main() {
         let message = match read_incoming_message(&mut io::stdin()) {
             Ok(m) => m,
             Err(e) => {
-                log::error!("Failed to deserialize incoming message: {:?}", e);
-                // Best to restart here because maybe our IO went out of sync
-                panic!("Forcing restart due to deserialization failure.");
+                handle_read_error(e);
+                continue;
             }
         };
 
@@ -256,9 +274,8 @@ fn main() {
             let message = match read_incoming_message(&mut io::stdin()) {
                 Ok(m) => m,
                 Err(e) => {
-                    log::error!("Failed to deserialize incoming message: {:?}", e);
-                    // Best to restart here because maybe our IO went out of sync
-                    panic!("Forcing restart due to deserialization failure.");
+                    handle_read_error(e);
+                    continue;
                 }
             };
 
@@ -267,21 +284,6 @@ fn main() {
             let context_clone = context.clone();
 
             pool.execute(move || {
-                /*
-                // TODO Lock SI when updating profile list over IPC
-                // SI lock
-                let lock_path = data_dir.join("si.lock");
-                // Create/open SI lockfile
-                let lock_file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&lock_path)
-                    .expect("Failed to open single-instance lock!");
-                // Lock the lockfile
-                lock_file.lock_exclusive()
-                    .expect("Failed to grab single-instance lock!");
-                 */
-
                 let response = execute_cmd_for_message(&context_clone, message.msg);
 
                 log::trace!("Message {} processed, response is: {:?}", &message.id, &response);
@@ -297,4 +299,44 @@ fn main() {
     });
 
     windowing.run_event_loop();
-}
\ No newline at end of file
+}
+
+pub fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("ax.nd",
+                      "nulldev",
+                      "FirefoxProfileSwitcher")
+        .expect("Could not initialize configuration (failed to find storage dir)!")
+}
+
+// Respond to messages we could not read, exit if we can no longer read any messages
+fn handle_read_error(error: ReadMessageError) {
+    match error {
+        ReadMessageError::EndOfStream => {
+            log::trace!("Browser closed the connection, exiting.");
+            std::process::exit(0);
+        }
+        ReadMessageError::FramingLost(e) => {
+            // Our IO is out of sync so we cannot trust any following messages
+            log::error!("Lost track of incoming messages, exiting: {:?}", e);
+            std::process::exit(1);
+        }
+        ReadMessageError::BadMessage { id, error } => {
+            log::error!("Failed to deserialize incoming message {:?}: {:?}", id, error);
+            if let Some(id) = id {
+                write_native_response(NativeResponseWrapper {
+                    id,
+                    resp: NativeResponse::error_with_dbg_msg(NativeErrorCode::InvalidMessage, "Received an invalid message!", error)
+                });
+            }
+        }
+        ReadMessageError::UnsupportedCommand { id, command } => {
+            log::error!("Received unsupported command {:?} in message {:?}", command, id);
+            if let Some(id) = id {
+                write_native_response(NativeResponseWrapper {
+                    id,
+                    resp: NativeResponse::error_with_dbg_str(NativeErrorCode::UnsupportedCommand, "Unsupported command, please update the connector.", command)
+                });
+            }
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use std::collections::HashMap;
 use serde_json::Value;
+use std::io;
 use std::io::Read;
 use byteorder::{ReadBytesExt, NativeEndian};
-use eyre::Context;
 use serde::{Deserialize, Serialize};
 
 // === NATIVE REQUEST ===
@@ -10,7 +10,10 @@ use serde::{Deserialize, Serialize};
 pub struct NativeMessageInitialize {
     pub extension_id: String,
     pub extension_version: Option<String>,
-    pub profile_id: Option<String>
+    pub profile_id: Option<String>,
+    /// Names of the `ProtocolFeature`s the extension understands, if missing they are derived
+    /// from `extension_version`
+    pub features: Option<Vec<String>>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,12 +26,14 @@ pub struct NativeMessageLaunchProfile {
 pub struct NativeMessageCreateProfile {
     pub name: String,
     pub avatar: String,
-    pub options: HashMap<String, Value>
+    pub options: HashMap<String, Value>,
+    pub template: Option<String>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NativeMessageDeleteProfile {
-    pub profile_id: String
+    pub profile_id: String,
+    pub expected_revision: Option<u64>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -37,7 +42,8 @@ pub struct NativeMessageUpdateProfile {
     pub name: String,
     pub avatar: Option<String>,
     pub options: HashMap<String, Value>,
-    pub default: bool
+    pub default: bool,
+    pub expected_revision: Option<u64>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -58,6 +64,138 @@ pub struct NativeMessageDeleteAvatar {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NativeMessageUpdateProfileOrder {
     pub order: Vec<String>,
+    pub expected_revision: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageCloneProfile {
+    pub profile_id: String,
+    pub name: String,
+    pub avatar: Option<String>,
+    pub options: Option<HashMap<String, Value>>
+}
+
+// The path is picked by the user if it is not specified
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageExportProfile {
+    pub profile_id: String,
+    pub path: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageImportProfile {
+    pub path: Option<String>,
+    // Defaults to the name stored in the archive
+    pub name: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageRestoreProfile {
+    pub trash_id: String,
+    // Defaults to the name the profile had when it was deleted
+    pub name: Option<String>
+}
+
+// Purges the whole trash if no entry is specified
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessagePurgeTrash {
+    pub trash_id: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageAdoptProfile {
+    // Path of the orphaned folder as reported by ScanProfiles
+    pub path: String,
+    // Defaults to a name derived from the folder name
+    pub name: Option<String>,
+    pub avatar: Option<String>,
+    pub options: Option<HashMap<String, Value>>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageRemoveDanglingEntry {
+    pub profile_id: String,
+    pub expected_revision: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageMoveProfile {
+    pub profile_id: String,
+    // Absolute path of the new profile folder, the profile is moved back into the browser profile
+    // dir if this is not specified
+    pub target: Option<String>,
+    pub expected_revision: Option<u64>
+}
+
+// The ephemeral profile starts empty (apart from the switcher extension) if no source profile is specified
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageLaunchEphemeralProfile {
+    pub source_profile_id: Option<String>,
+    pub template: Option<String>,
+    pub url: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageSaveProfileAsTemplate {
+    pub profile_id: String,
+    pub name: String,
+    // Ids of the addons in the profile to include in the template
+    #[serde(default)]
+    pub extension_ids: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageDeleteTemplate {
+    pub template_id: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageListExtensions {
+    pub profile_id: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageCopyExtensions {
+    pub source_profile_id: String,
+    pub target_profile_ids: Vec<String>,
+    pub extension_ids: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageGetPrefs {
+    pub profile_id: String
+}
+
+// The pref applies to all profiles if no profile is specified
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageSetPref {
+    pub profile_id: Option<String>,
+    pub name: String,
+    pub value: Value
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageRemovePref {
+    pub profile_id: Option<String>,
+    pub name: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageRestoreBackup {
+    pub backup_id: String,
+}
+
+/// Commands that can be applied as part of a batch
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command")]
+pub enum NativeMessageBatchStep {
+    UpdateProfile(NativeMessageUpdateProfile),
+    UpdateProfileOrder(NativeMessageUpdateProfileOrder),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NativeMessageBatch {
+    pub steps: Vec<NativeMessageBatchStep>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -74,6 +212,31 @@ pub enum NativeMessage {
     GetAvatar(NativeMessageGetAvatar),
     DeleteAvatar(NativeMessageDeleteAvatar),
     UpdateProfileOrder(NativeMessageUpdateProfileOrder),
+    Batch(NativeMessageBatch),
+    GetState,
+    ListBackups,
+    RestoreBackup(NativeMessageRestoreBackup),
+    RepairProfilesIni,
+    CloneProfile(NativeMessageCloneProfile),
+    ExportProfile(NativeMessageExportProfile),
+    ImportProfile(NativeMessageImportProfile),
+    ListTrash,
+    RestoreProfile(NativeMessageRestoreProfile),
+    PurgeTrash(NativeMessagePurgeTrash),
+    ScanProfiles,
+    AdoptProfile(NativeMessageAdoptProfile),
+    RemoveDanglingEntry(NativeMessageRemoveDanglingEntry),
+    MoveProfile(NativeMessageMoveProfile),
+    LaunchEphemeralProfile(NativeMessageLaunchEphemeralProfile),
+    ListTemplates,
+    SaveProfileAsTemplate(NativeMessageSaveProfileAsTemplate),
+    DeleteTemplate(NativeMessageDeleteTemplate),
+    GetPrefs(NativeMessageGetPrefs),
+    SetPref(NativeMessageSetPref),
+    RemovePref(NativeMessageRemovePref),
+    ListExtensions(NativeMessageListExtensions),
+    CopyExtensions(NativeMessageCopyExtensions),
+    SyncSwitcherExtension,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -81,16 +244,136 @@ pub struct NativeMessageWrapper {
     pub id: i64,
     pub msg: NativeMessage
 }
-pub fn read_incoming_message(input: &mut impl Read) -> eyre::Result<NativeMessageWrapper> {
+/// Names of every command in `NativeMessage`, used to tell unknown commands apart from malformed ones
+pub const NATIVE_MESSAGE_COMMANDS: &[&str] = &[
+    "Initialize",
+    "LaunchProfile",
+    "CreateProfile",
+    "DeleteProfile",
+    "UpdateProfile",
+    "UpdateOptions",
+    "CloseManager",
+    "AddAvatars",
+    "GetAvatar",
+    "DeleteAvatar",
+    "UpdateProfileOrder",
+    "Batch",
+    "GetState",
+    "ListBackups",
+    "RestoreBackup",
+    "RepairProfilesIni",
+    "CloneProfile",
+    "ExportProfile",
+    "ImportProfile",
+    "ListTrash",
+    "RestoreProfile",
+    "PurgeTrash",
+    "ScanProfiles",
+    "AdoptProfile",
+    "RemoveDanglingEntry",
+    "MoveProfile",
+    "LaunchEphemeralProfile",
+    "ListTemplates",
+    "SaveProfileAsTemplate",
+    "DeleteTemplate",
+    "GetPrefs",
+    "SetPref",
+    "RemovePref",
+    "ListExtensions",
+    "CopyExtensions",
+    "SyncSwitcherExtension",
+];
+
+#[derive(Debug)]
+pub enum ReadMessageError {
+    /// The browser closed our stdin, no more messages will arrive
+    EndOfStream,
+    /// The message could not be read completely, the input stream is now out of sync
+    FramingLost(io::Error),
+    /// The message was read fully but its body is invalid
+    BadMessage { id: Option<i64>, error: serde_json::Error },
+    /// The message is valid but contains a command we do not know about (e.g. sent by a newer extension)
+    UnsupportedCommand { id: Option<i64>, command: String }
+}
+
+pub fn read_incoming_message(input: &mut impl Read) -> Result<NativeMessageWrapper, ReadMessageError> {
     // Read size of incoming message
-    let size = input.read_u32::<NativeEndian>()
-        .context("Failed to read native message size!")?;
+    let size = match input.read_u32::<NativeEndian>() {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(ReadMessageError::EndOfStream),
+        Err(e) => return Err(ReadMessageError::FramingLost(e))
+    };
 
     // Read and deserialize
     let mut conf_buffer = vec![0u8; size as usize];
     input.read_exact(&mut conf_buffer)
-        .context("Failed to read native message!")?;
-    serde_json::from_slice(&conf_buffer)
-        .context("Failed to deserialize native message!")
+        .map_err(ReadMessageError::FramingLost)?;
+    let raw: Value = serde_json::from_slice(&conf_buffer)
+        .map_err(|error| ReadMessageError::BadMessage { id: None, error })?;
+
+    // Keep the ID around so we can still respond to the message if the rest of it is bad
+    let id = raw.get("id").and_then(Value::as_i64);
+    let command = raw.get("msg")
+        .and_then(|m| m.get("command"))
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+
+    serde_json::from_value(raw).map_err(|error| match command {
+        Some(command) if !NATIVE_MESSAGE_COMMANDS.contains(&command.as_str()) =>
+            ReadMessageError::UnsupportedCommand { id, command },
+        _ => ReadMessageError::BadMessage { id, error }
+    })
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+    use super::*;
+
+    fn frame(body: &[u8]) -> Cursor<Vec<u8>> {
+        let mut input = Vec::new();
+        input.write_u32::<NativeEndian>(body.len() as u32).unwrap();
+        input.extend_from_slice(body);
+        Cursor::new(input)
+    }
+
+    #[test]
+    fn command_names_match_the_commands() {
+        let error = serde_json::from_str::<NativeMessage>(r#"{"command":"NoSuchCommand"}"#)
+            .unwrap_err()
+            .to_string();
+        // serde lists every variant of the enum in the error, followed by the error position
+        let expected = error.split("expected one of ").nth(1).unwrap()
+            .split(" at line ").next().unwrap();
+        let variants: Vec<&str> = expected.split(", ").map(|v| v.trim_matches('`')).collect();
+        assert_eq!(variants, NATIVE_MESSAGE_COMMANDS);
+    }
+
+    #[test]
+    fn read_incoming_message_errors() {
+        assert!(matches!(read_incoming_message(&mut Cursor::new(Vec::new())), Err(ReadMessageError::EndOfStream)));
+
+        let mut truncated = frame(br#"{"id":1,"msg":{"command":"GetState"}}"#).into_inner();
+        truncated.truncate(10);
+        assert!(matches!(read_incoming_message(&mut Cursor::new(truncated)), Err(ReadMessageError::FramingLost(_))));
+
+        assert!(matches!(read_incoming_message(&mut frame(b"{not json")), Err(ReadMessageError::BadMessage { id: None, .. })));
+        assert!(matches!(
+            read_incoming_message(&mut frame(br#"{"id":4,"msg":{"command":"LaunchProfile"}}"#)),
+            Err(ReadMessageError::BadMessage { id: Some(4), .. })
+        ));
+
+        match read_incoming_message(&mut frame(br#"{"id":5,"msg":{"command":"FromTheFuture"}}"#)) {
+            Err(ReadMessageError::UnsupportedCommand { id, command }) => {
+                assert_eq!(id, Some(5));
+                assert_eq!(command, "FromTheFuture");
+            }
+            r => panic!("Unexpected result: {:?}", r)
+        }
+
+        let msg = read_incoming_message(&mut frame(br#"{"id":6,"msg":{"command":"GetState"}}"#)).unwrap();
+        assert_eq!(msg.id, 6);
+        assert!(matches!(msg.msg, NativeMessage::GetState));
+    }
+}
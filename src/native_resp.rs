@@ -3,28 +3,199 @@
 use std::fmt::Debug;
 use std::collections::HashMap;
 use serde_json::Value;
-use crate::profiles::ProfileEntry;
-use std::io;
+use crate::profiles::{ProfileEntry, ProfilesIniRepair, ProfilesIniWarning, ReadProfilesError, WriteProfilesError};
+use crate::options::WriteGlobalOptionsError;
+use crate::process::ForkBrowserProcError;
+use crate::profile_archive::{ExportProfileError, ImportProfileError};
+use crate::trash::{TrashEntry, TrashError};
+use crate::profile_scan::ProfileScan;
+use crate::templates::{TemplateError, TemplateInfo};
+use crate::prefs::{Prefs, PrefsError, PrefsWriteResult};
+use crate::extensions::{AddonSyncResult, ExtensionInfo, ExtensionsError};
+use std::{cmp, io};
 use byteorder::{NativeEndian, WriteBytesExt};
 use std::io::Write;
 use serde::{Serialize};
+use crate::versions::{ConnectorCapabilities, extension_supports, ProtocolFeature};
 
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
 pub enum NativeResponse {
     Error {
         success: bool,
+        code: NativeErrorCode,
         error: String,
-        debug_msg: Option<String>
+        debug_msg: Option<String>,
+        #[serde(flatten)]
+        details: Option<NativeErrorDetails>
     },
     Success {
         success: bool,
+        // Boxed so that errors returned by the command helpers stay small
         #[serde(flatten)]
-        data: NativeResponseData
+        data: Box<NativeResponseData>
     },
     Event(NativeResponseEvent)
 }
 
+/// Information about an error that the extension may need to resolve it
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum NativeErrorDetails {
+    AmbiguousProfiles {
+        matching_profile_ids: Vec<String>
+    }
+}
+
+/// Stable identifier for the cause of an error, the extension should use this instead of the error
+/// message to react to specific errors
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativeErrorCode {
+    // Protocol
+    InvalidMessage,
+    UnsupportedCommand,
+    NotInitialized,
+    AlreadyInitialized,
+    ResponseTooLarge,
+    ProfileDetectionFailed,
+    ProfileDetectionAmbiguous,
+    // Profiles
+    NameConflict,
+    ProfileNotFound,
+    ProfileInUse,
+    RevisionConflict,
+    ProfileDirFailed,
+    ProfileCopyFailed,
+    ProfileNotShutDown,
+    OrphanNotFound,
+    ProfileNotDangling,
+    InvalidMoveTarget,
+    MoveTargetExists,
+    ProfileMoveFailed,
+    // Launching
+    BinaryNotFound,
+    BinaryDoesNotExist,
+    LaunchFailed,
+    // Avatars
+    AvatarNotFound,
+    AvatarTooLarge,
+    InvalidAvatar,
+    AvatarStoreFailed,
+    // Storage
+    ProfilesIniInvalid,
+    StoreReadFailed,
+    StoreWriteFailed,
+    StoreBusy,
+    BackupNotFound,
+    // Archives
+    ArchiveReadFailed,
+    ArchiveWriteFailed,
+    ArchiveTargetExists,
+    InvalidArchive,
+    NoFileSelected,
+    // Trash
+    TrashEntryNotFound,
+    TrashFailed,
+    // Templates
+    TemplateNotFound,
+    ExtensionNotFound,
+    TemplateFailed,
+    // Prefs
+    InvalidPref,
+    PrefsWriteFailed,
+    // Extensions
+    ExtensionsFailed,
+}
+
+impl From<&ReadProfilesError> for NativeErrorCode {
+    fn from(e: &ReadProfilesError) -> Self {
+        match e {
+            ReadProfilesError::IniError(ini::Error::Parse(_)) => NativeErrorCode::ProfilesIniInvalid,
+            _ => NativeErrorCode::StoreReadFailed
+        }
+    }
+}
+
+impl From<&WriteProfilesError> for NativeErrorCode {
+    fn from(_: &WriteProfilesError) -> Self {
+        NativeErrorCode::StoreWriteFailed
+    }
+}
+
+impl From<&WriteGlobalOptionsError> for NativeErrorCode {
+    fn from(_: &WriteGlobalOptionsError) -> Self {
+        NativeErrorCode::StoreWriteFailed
+    }
+}
+
+impl From<&ForkBrowserProcError> for NativeErrorCode {
+    fn from(e: &ForkBrowserProcError) -> Self {
+        match e {
+            ForkBrowserProcError::BinaryNotFound => NativeErrorCode::BinaryNotFound,
+            ForkBrowserProcError::BinaryDoesNotExist => NativeErrorCode::BinaryDoesNotExist,
+            _ => NativeErrorCode::LaunchFailed
+        }
+    }
+}
+
+impl From<&ExportProfileError> for NativeErrorCode {
+    fn from(e: &ExportProfileError) -> Self {
+        match e {
+            ExportProfileError::TargetExists => NativeErrorCode::ArchiveTargetExists,
+            _ => NativeErrorCode::ArchiveWriteFailed
+        }
+    }
+}
+
+impl From<&ImportProfileError> for NativeErrorCode {
+    fn from(e: &ImportProfileError) -> Self {
+        match e {
+            ImportProfileError::ReadArchiveError(_) => NativeErrorCode::ArchiveReadFailed,
+            ImportProfileError::AvatarTooLarge => NativeErrorCode::AvatarTooLarge,
+            ImportProfileError::WriteProfileError(_) => NativeErrorCode::ProfileDirFailed,
+            ImportProfileError::WriteAvatarError(_) => NativeErrorCode::AvatarStoreFailed,
+            _ => NativeErrorCode::InvalidArchive
+        }
+    }
+}
+
+impl From<&TrashError> for NativeErrorCode {
+    fn from(e: &TrashError) -> Self {
+        match e {
+            TrashError::EntryNotFound => NativeErrorCode::TrashEntryNotFound,
+            _ => NativeErrorCode::TrashFailed
+        }
+    }
+}
+
+impl From<&TemplateError> for NativeErrorCode {
+    fn from(e: &TemplateError) -> Self {
+        match e {
+            TemplateError::TemplateNotFound => NativeErrorCode::TemplateNotFound,
+            TemplateError::ExtensionNotFound(_) => NativeErrorCode::ExtensionNotFound,
+            _ => NativeErrorCode::TemplateFailed
+        }
+    }
+}
+
+impl From<&ExtensionsError> for NativeErrorCode {
+    fn from(e: &ExtensionsError) -> Self {
+        match e {
+            ExtensionsError::ExtensionNotFound(_) => NativeErrorCode::ExtensionNotFound,
+            _ => NativeErrorCode::ExtensionsFailed
+        }
+    }
+}
+
+impl From<&PrefsError> for NativeErrorCode {
+    fn from(e: &PrefsError) -> Self {
+        match e {
+            PrefsError::InvalidName | PrefsError::InvalidValue => NativeErrorCode::InvalidPref,
+            _ => NativeErrorCode::PrefsWriteFailed
+        }
+    }
+}
+
 pub const NATIVE_RESP_ID_EVENT: i64 = -1;
 
 #[derive(Serialize)]
@@ -43,31 +214,46 @@ impl NativeResponseWrapper {
 }
 
 impl NativeResponse {
-    pub fn error<S: Into<String>>(msg: S) -> NativeResponse {
+    pub fn error<S: Into<String>>(code: NativeErrorCode, msg: S) -> NativeResponse {
+        NativeResponse::Error {
+            success: false,
+            code,
+            error: msg.into(),
+            debug_msg: None,
+            details: None
+        }
+    }
+    pub fn error_with_dbg_msg<S: Into<String>>(code: NativeErrorCode, msg: S, err: impl Debug) -> NativeResponse {
         NativeResponse::Error {
             success: false,
+            code,
             error: msg.into(),
-            debug_msg: None
+            debug_msg: Some(format!("{:?}", err)),
+            details: None
         }
     }
-    pub fn error_with_dbg_msg<S: Into<String>>(msg: S, err: impl Debug) -> NativeResponse {
+    pub fn error_with_dbg_str<S: Into<String>>(code: NativeErrorCode, msg: S, err: String) -> NativeResponse {
         NativeResponse::Error {
             success: false,
+            code,
             error: msg.into(),
-            debug_msg: Some(format!("{:?}", err))
+            debug_msg: Some(err),
+            details: None
         }
     }
-    pub fn error_with_dbg_str<S: Into<String>>(msg: S, err: String) -> NativeResponse {
+    pub fn error_with_details<S: Into<String>>(code: NativeErrorCode, msg: S, details: NativeErrorDetails) -> NativeResponse {
         NativeResponse::Error {
             success: false,
+            code,
             error: msg.into(),
-            debug_msg: Some(err)
+            debug_msg: None,
+            details: Some(details)
         }
     }
     pub fn success(data: NativeResponseData) -> NativeResponse {
         NativeResponse::Success {
             success: true,
-            data
+            data: Box::new(data)
         }
     }
     fn event(event: NativeResponseEvent) -> NativeResponse {
@@ -81,7 +267,8 @@ pub struct NativeResponseProfileListProfileEntry {
     pub name: String,
     pub default: bool,
     pub avatar: Option<String>,
-    pub options: HashMap<String, Value>
+    pub options: HashMap<String, Value>,
+    pub revision: u64
 }
 
 impl NativeResponseProfileListProfileEntry {
@@ -91,7 +278,8 @@ impl NativeResponseProfileListProfileEntry {
             name: entry.name.clone(),
             default: entry.default,
             avatar: entry.avatar.clone(),
-            options: entry.options.clone()
+            options: entry.options.clone(),
+            revision: entry.revision
         }
     }
 }
@@ -100,7 +288,9 @@ impl NativeResponseProfileListProfileEntry {
 #[serde(untagged)]
 pub enum NativeResponseData {
     Initialized {
-        cached: bool
+        cached: bool,
+        #[serde(flatten)]
+        capabilities: ConnectorCapabilities
     },
     ProfileLaunched,
     ProfileCreated {
@@ -109,7 +299,9 @@ pub enum NativeResponseData {
     ProfileUpdated {
         profile: NativeResponseProfileListProfileEntry
     },
-    ProfileDeleted,
+    ProfileDeleted {
+        trash_id: String
+    },
     OptionsUpdated {
         options: HashMap<String, Value>
     },
@@ -118,29 +310,280 @@ pub enum NativeResponseData {
     GetAvatarResult { data: String, mime: String },
     AvatarDeleted,
     ProfileOrderUpdated,
+    BatchApplied {
+        results: Vec<NativeResponseData>
+    },
+    State {
+        current_profile_id: Option<String>,
+        profiles: Vec<NativeResponseProfileListProfileEntry>,
+        options: HashMap<String, Value>,
+        avatars: Vec<String>,
+        order: Vec<String>,
+        order_revision: u64,
+        revision: u64,
+        warnings: Vec<ProfilesIniWarning>
+    },
+    Backups {
+        backups: Vec<String>
+    },
+    BackupRestored,
+    ProfileCloned {
+        profile: NativeResponseProfileListProfileEntry
+    },
+    ProfileExported {
+        path: String
+    },
+    ProfileImported {
+        profile: NativeResponseProfileListProfileEntry
+    },
+    Trash {
+        entries: Vec<TrashEntry>
+    },
+    ProfileRestored {
+        profile: NativeResponseProfileListProfileEntry
+    },
+    TrashPurged,
+    ProfileScan {
+        #[serde(flatten)]
+        scan: ProfileScan
+    },
+    ProfileAdopted {
+        profile: NativeResponseProfileListProfileEntry
+    },
+    DanglingEntryRemoved,
+    ProfileMoved {
+        profile: NativeResponseProfileListProfileEntry
+    },
+    EphemeralProfileLaunched {
+        profile: NativeResponseProfileListProfileEntry
+    },
+    Templates {
+        templates: Vec<TemplateInfo>
+    },
+    TemplateSaved {
+        template: TemplateInfo
+    },
+    TemplateDeleted,
+    Prefs {
+        /// Prefs that apply to all profiles
+        global: Prefs,
+        /// Prefs of the profile, these take precedence over the global prefs
+        profile: Prefs,
+        /// Values of the managed prefs the browser currently uses (from prefs.js)
+        current: Prefs,
+        /// The profile was running when its prefs changed, they are written once it is closed
+        pending: bool
+    },
+    PrefsUpdated {
+        results: HashMap<String, PrefsWriteResult>
+    },
+    Extensions {
+        extensions: Vec<ExtensionInfo>
+    },
+    ExtensionsCopied,
+    SwitcherExtensionSynced {
+        results: HashMap<String, AddonSyncResult>
+    },
+    ProfilesIniRepaired {
+        repairs: Vec<ProfilesIniRepair>,
+        warnings: Vec<ProfilesIniWarning>
+    },
 }
 
+/// Names of every event in `NativeResponseEvent`, reported to the extension during initialization
+pub const NATIVE_RESPONSE_EVENTS: &[&str] = &[
+    "ProfileList",
+    "FocusWindow",
+    "CloseManager",
+    "ConnectorInformation",
+    "OptionsUpdated",
+    "AvatarsUpdated",
+    "ProfileOrderUpdated",
+];
+
 #[derive(Serialize, Debug)]
 #[serde(tag = "event")]
 pub enum NativeResponseEvent {
-    ProfileList { current_profile_id: String, profiles: Vec<NativeResponseProfileListProfileEntry> },
+    ProfileList { current_profile_id: String, profiles: Vec<NativeResponseProfileListProfileEntry>, warnings: Vec<ProfilesIniWarning> },
     FocusWindow { url: Option<String> },
     CloseManager,
     ConnectorInformation { version: String },
     OptionsUpdated { options: HashMap<String, Value> },
     AvatarsUpdated { avatars: Vec<String> },
-    ProfileOrderUpdated { order: Vec<String> },
+    ProfileOrderUpdated { order: Vec<String>, revision: u64 },
+}
+
+// Firefox drops any message from the native app that is larger than 1 MB
+const MAX_NATIVE_MESSAGE_SIZE: usize = 1024 * 1024;
+// Size of the serialized response carried by each chunk. The chunk data is re-escaped as a JSON
+// string which can at most double its size (quotes and backslashes), so this leaves plenty of room.
+const NATIVE_RESP_CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Serialize)]
+struct NativeResponseChunk<'a> {
+    id: i64,
+    chunk: NativeResponseChunkInfo,
+    data: &'a str
+}
+
+#[derive(Serialize)]
+struct NativeResponseChunkInfo {
+    seq: u32,
+    #[serde(rename = "final")]
+    is_final: bool
+}
+
+/// Split a serialized response into pieces of at most `NATIVE_RESP_CHUNK_SIZE` bytes, making sure
+/// not to split any UTF-8 characters.
+fn split_into_chunks(serialized: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut remaining = serialized;
+    while !remaining.is_empty() {
+        let mut end = cmp::min(NATIVE_RESP_CHUNK_SIZE, remaining.len());
+        while !remaining.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, rest) = remaining.split_at(end);
+        chunks.push(chunk);
+        remaining = rest;
+    }
+    chunks
+}
+
+fn write_native_frame(handle: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    handle.write_u32::<NativeEndian>(frame.len() as u32)?;
+    handle.write_all(frame)?;
+    handle.flush()
+}
+
+fn write_chunked_response(handle: &mut impl Write, id: i64, serialized: &str) -> io::Result<()> {
+    let chunks = split_into_chunks(serialized);
+    log::trace!("Response {} is {} bytes, splitting into {} chunks", id, serialized.len(), chunks.len());
+    let last_seq = chunks.len() - 1;
+    chunks.iter()
+        .enumerate()
+        .try_for_each(|(seq, data)| {
+            let frame = serde_json::to_vec(&NativeResponseChunk {
+                id,
+                chunk: NativeResponseChunkInfo {
+                    seq: seq as u32,
+                    is_final: seq == last_seq
+                },
+                data
+            }).unwrap();
+            write_native_frame(handle, &frame)
+        })
 }
 
 pub fn write_native_response(resp: NativeResponseWrapper) {
-    let serialized = serde_json::to_vec(&resp).unwrap();
-    // TODO Handle error
+    let serialized = serde_json::to_string(&resp).unwrap();
+    // Hold the lock until every chunk is written so responses from other threads are not interleaved
     let mut handle = io::stdout().lock();
-    handle.write_u32::<NativeEndian>(serialized.len() as u32);
-    handle.write_all(&serialized);
-    handle.flush();
+    let result = if serialized.len() <= MAX_NATIVE_MESSAGE_SIZE {
+        write_native_frame(&mut handle, serialized.as_bytes())
+    } else if !extension_supports(ProtocolFeature::ChunkedResponses) {
+        // The extension would silently drop the response, tell it what happened instead. Events are
+        // replaced by an error event so the extension knows it missed one and can ask for the state.
+        log::error!("Response {} is {} bytes but the extension does not support chunked responses!", resp.id, serialized.len());
+        let error_resp = serde_json::to_vec(&NativeResponseWrapper {
+            id: resp.id,
+            resp: NativeResponse::error(NativeErrorCode::ResponseTooLarge, "Response is too large for this version of the extension, please update the extension.")
+        }).unwrap();
+        write_native_frame(&mut handle, &error_resp)
+    } else {
+        write_chunked_response(&mut handle, resp.id, &serialized)
+    };
+    if let Err(e) = result {
+        log::error!("Failed to write native response {}: {:?}", resp.id, e);
+    }
 }
 
 pub fn write_native_event(resp: NativeResponseEvent) {
     write_native_response(NativeResponseWrapper::event(resp));
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use byteorder::ReadBytesExt;
+    use super::*;
+
+    #[test]
+    fn event_names_match_the_events() {
+        let events = vec![
+            NativeResponseEvent::ProfileList { current_profile_id: String::new(), profiles: Vec::new(), warnings: Vec::new() },
+            NativeResponseEvent::FocusWindow { url: None },
+            NativeResponseEvent::CloseManager,
+            NativeResponseEvent::ConnectorInformation { version: String::new() },
+            NativeResponseEvent::OptionsUpdated { options: HashMap::new() },
+            NativeResponseEvent::AvatarsUpdated { avatars: Vec::new() },
+            NativeResponseEvent::ProfileOrderUpdated { order: Vec::new(), revision: 0 },
+        ];
+        // Fails to compile when an event is added so it cannot be forgotten above
+        for event in &events {
+            match event {
+                NativeResponseEvent::ProfileList { .. } | NativeResponseEvent::FocusWindow { .. }
+                | NativeResponseEvent::CloseManager | NativeResponseEvent::ConnectorInformation { .. }
+                | NativeResponseEvent::OptionsUpdated { .. } | NativeResponseEvent::AvatarsUpdated { .. }
+                | NativeResponseEvent::ProfileOrderUpdated { .. } => {}
+            }
+        }
+
+        let names: Vec<String> = events.iter()
+            .map(|e| serde_json::to_value(e).unwrap()["event"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, NATIVE_RESPONSE_EVENTS);
+    }
+
+    #[test]
+    fn small_responses_are_a_single_chunk() {
+        assert_eq!(split_into_chunks("{}"), vec!["{}"]);
+
+        let exact = "a".repeat(NATIVE_RESP_CHUNK_SIZE);
+        assert_eq!(split_into_chunks(&exact), vec![exact.as_str()]);
+    }
+
+    #[test]
+    fn chunks_are_filled_up() {
+        let serialized = "a".repeat(NATIVE_RESP_CHUNK_SIZE * 3);
+        let chunks = split_into_chunks(&serialized);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c.len() == NATIVE_RESP_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn characters_are_not_split() {
+        // The two byte character starts at the last byte of the first chunk
+        let serialized = format!("{}é{}", "a".repeat(NATIVE_RESP_CHUNK_SIZE - 1), "a".repeat(10));
+        let chunks = split_into_chunks(&serialized);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), NATIVE_RESP_CHUNK_SIZE - 1);
+        assert!(chunks[1].starts_with('é'));
+        assert_eq!(chunks.concat(), serialized);
+    }
+
+    #[test]
+    fn only_the_last_chunk_is_final() {
+        let serialized = "a".repeat(NATIVE_RESP_CHUNK_SIZE * 2 + 1);
+        let mut output = Vec::new();
+        write_chunked_response(&mut output, 7, &serialized).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut frames = Vec::new();
+        while let Ok(len) = reader.read_u32::<NativeEndian>() {
+            let mut frame = vec![0; len as usize];
+            reader.read_exact(&mut frame).unwrap();
+            assert!(frame.len() <= MAX_NATIVE_MESSAGE_SIZE);
+            frames.push(serde_json::from_slice::<Value>(&frame).unwrap());
+        }
+
+        assert_eq!(frames.len(), 3);
+        for (seq, frame) in frames.iter().enumerate() {
+            assert_eq!(frame["id"], 7);
+            assert_eq!(frame["chunk"]["seq"], seq);
+            assert_eq!(frame["chunk"]["final"], seq == 2);
+        }
+        let data: String = frames.iter().map(|f| f["data"].as_str().unwrap()).collect();
+        assert_eq!(data, serialized);
+    }
+}
@@ -1,9 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use serde_json::Value;
 use std::fs::OpenOptions;
 use std::io;
-use crate::storage::global_options_data_path;
+use crate::storage::{global_options_data_path, write_file_atomic};
 use crate::native_resp::{write_native_event, NativeResponseEvent};
 use crate::state::AppState;
 
@@ -23,20 +23,16 @@ pub fn read_global_options(path: &PathBuf) -> HashMap<String, Value> {
 
 #[derive(Debug)]
 pub enum WriteGlobalOptionsError {
-    OpenFileError(io::Error),
-    WriteFileError(serde_json::Error)
+    WriteFileError(io::Error),
+    SerializeError(serde_json::Error)
 }
 
 //// Read global options to the specified file
-pub fn write_global_options(path: &PathBuf, new_options: &HashMap<String, Value>) -> Result<(), WriteGlobalOptionsError> {
-    let options_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(path)
-        .map_err(WriteGlobalOptionsError::OpenFileError)?;
-
-    serde_json::to_writer(options_file, &new_options)
+pub fn write_global_options(path: &Path, new_options: &HashMap<String, Value>) -> Result<(), WriteGlobalOptionsError> {
+    let serialized = serde_json::to_vec(&new_options)
+        .map_err(WriteGlobalOptionsError::SerializeError)?;
+
+    write_file_atomic(path, &serialized)
         .map_err(WriteGlobalOptionsError::WriteFileError)
 }
 
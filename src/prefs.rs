@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::config::Config;
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
+use crate::profiles::ProfilesIniState;
+use crate::storage::{prefs_data_path, write_file_atomic};
+
+// === MANAGED PREFS ===
+
+// Managed prefs are written into a delimited block in the user.js of each profile, the browser
+// applies user.js on startup. The rest of user.js belongs to the user and is never touched.
+// The browser reads user.js when it starts so profiles that are running get their block written
+// the next time they are closed (see `sync_pending_prefs`).
+const BLOCK_BEGIN: &str = "// BEGIN PROFILE SWITCHER MANAGED PREFS (changes in this block will be overwritten)";
+const BLOCK_END: &str = "// END PROFILE SWITCHER MANAGED PREFS";
+const USER_JS_FILENAME: &str = "user.js";
+const PREFS_JS_FILENAME: &str = "prefs.js";
+
+pub type Prefs = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PrefsData {
+    /// Prefs applied to every profile
+    #[serde(default)]
+    pub global: Prefs,
+    /// Prefs of each profile, these take precedence over the global prefs
+    #[serde(default)]
+    pub profiles: HashMap<String, Prefs>,
+    /// Profiles whose user.js could not be updated because they were running
+    #[serde(default)]
+    pub pending: BTreeSet<String>
+}
+
+#[derive(Debug)]
+pub enum PrefsError {
+    InvalidName,
+    InvalidValue,
+    WriteStoreError(io::Error),
+    SerializeStoreError(serde_json::Error),
+    ReadUserJsError(io::Error),
+    WriteUserJsError(io::Error)
+}
+
+impl fmt::Display for PrefsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrefsError::InvalidName => write!(f, "invalid pref name"),
+            PrefsError::InvalidValue => write!(f, "prefs must be booleans, strings or 32-bit integers"),
+            PrefsError::WriteStoreError(e) => write!(f, "failed to write the prefs store: {}", e),
+            PrefsError::SerializeStoreError(e) => write!(f, "failed to serialize the prefs store: {}", e),
+            PrefsError::ReadUserJsError(e) => write!(f, "failed to read user.js: {}", e),
+            PrefsError::WriteUserJsError(e) => write!(f, "failed to write user.js: {}", e)
+        }
+    }
+}
+
+/// Whether a managed pref was written to user.js
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefsWriteResult {
+    Applied,
+    /// The profile is running, the pref will be written when it is closed
+    Deferred
+}
+
+impl PrefsData {
+    pub fn read(config_dir: &Path) -> PrefsData {
+        OpenOptions::new()
+            .read(true)
+            .open(prefs_data_path(config_dir))
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|f| serde_json::from_reader(f).map_err(|e| format!("{:?}", e)))
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to read prefs data: {}, falling back to defaults", e);
+                PrefsData::default()
+            })
+    }
+
+    pub fn write(&mut self, config_dir: &Path, profiles: &ProfilesIniState) -> Result<(), PrefsError> {
+        // Forget deleted profiles
+        self.profiles.retain(|id, _| profiles.profile_entries.iter().any(|p| &p.id == id));
+        self.pending.retain(|id| profiles.profile_entries.iter().any(|p| &p.id == id));
+
+        let serialized = serde_json::to_vec(self).map_err(PrefsError::SerializeStoreError)?;
+        write_file_atomic(&prefs_data_path(config_dir), &serialized)
+            .map_err(PrefsError::WriteStoreError)
+    }
+
+    /// The prefs that are written into the user.js of a profile
+    pub fn effective_prefs(&self, profile_id: &str) -> Prefs {
+        let mut prefs = self.global.clone();
+        if let Some(profile_prefs) = self.profiles.get(profile_id) {
+            prefs.extend(profile_prefs.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        prefs
+    }
+
+    /// Write the managed prefs into the user.js of a profile unless it is running, in which case
+    /// the profile is marked as pending
+    pub fn apply(&mut self, profile_id: &str, profile_dir: &Path, config: &Config) -> Result<PrefsWriteResult, PrefsError> {
+        if detect_profile_lock_state(profile_dir, config) == ProfileLockState::Running {
+            self.pending.insert(profile_id.to_owned());
+            return Ok(PrefsWriteResult::Deferred)
+        }
+        write_managed_prefs(profile_dir, &self.effective_prefs(profile_id))?;
+        self.pending.remove(profile_id);
+        Ok(PrefsWriteResult::Applied)
+    }
+}
+
+pub fn validate_pref(name: &str, value: &Value) -> Result<(), PrefsError> {
+    if name.is_empty() || name.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        return Err(PrefsError::InvalidName)
+    }
+    match value {
+        Value::Bool(_) | Value::String(_) => Ok(()),
+        // The browser only supports 32-bit integer prefs
+        Value::Number(n) if n.as_i64().is_some_and(|n| i32::try_from(n).is_ok()) => Ok(()),
+        _ => Err(PrefsError::InvalidValue)
+    }
+}
+
+/// Write the managed prefs into the user.js of the profiles that were running when their prefs
+/// changed. Will log if a user.js cannot be written. The store lock must be held.
+pub fn sync_pending_prefs(config_dir: &Path, config: &Config, profiles: &ProfilesIniState) {
+    let mut prefs_data = PrefsData::read(config_dir);
+    if prefs_data.pending.is_empty() {
+        return
+    }
+
+    for profile in &profiles.profile_entries {
+        if prefs_data.pending.contains(&profile.id) {
+            if let Err(e) = prefs_data.apply(&profile.id, &profile.full_path(config), config) {
+                log::error!("Failed to write pending prefs of profile {}: {}", profile.id, e);
+            }
+        }
+    }
+    if let Err(e) = prefs_data.write(config_dir, profiles) {
+        log::error!("Failed to update prefs data: {}", e);
+    }
+}
+
+/// Replace the managed block in the user.js of a profile, the block is added to the end of the
+/// file if it does not have one yet
+pub fn write_managed_prefs(profile_dir: &Path, prefs: &Prefs) -> Result<(), PrefsError> {
+    let user_js_path = profile_dir.join(USER_JS_FILENAME);
+    let user_js = match fs::read_to_string(&user_js_path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if prefs.is_empty() {
+                return Ok(())
+            }
+            String::new()
+        }
+        Err(e) => return Err(PrefsError::ReadUserJsError(e))
+    };
+
+    let new_user_js = replace_managed_block(&user_js, prefs);
+    if new_user_js != user_js {
+        write_file_atomic(&user_js_path, new_user_js.as_bytes()).map_err(PrefsError::WriteUserJsError)?;
+    }
+    Ok(())
+}
+
+fn replace_managed_block(user_js: &str, prefs: &Prefs) -> String {
+    let mut block = String::new();
+    if !prefs.is_empty() {
+        block.push_str(BLOCK_BEGIN);
+        block.push('\n');
+        for (name, value) in prefs {
+            block.push_str(&format!("user_pref(\"{}\", {});\n", name, format_pref_value(value)));
+        }
+        block.push_str(BLOCK_END);
+        block.push('\n');
+    }
+
+    match (user_js.find(BLOCK_BEGIN), user_js.find(BLOCK_END)) {
+        (Some(begin), Some(end)) if begin < end => {
+            let mut after = &user_js[end + BLOCK_END.len()..];
+            after = after.strip_prefix("\r\n").or_else(|| after.strip_prefix('\n')).unwrap_or(after);
+            format!("{}{}{}", &user_js[..begin], block, after)
+        }
+        _ if block.is_empty() => user_js.to_owned(),
+        _ if user_js.is_empty() || user_js.ends_with('\n') => format!("{}{}", user_js, block),
+        _ => format!("{}\n{}", user_js, block)
+    }
+}
+
+fn format_pref_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => {
+            let escaped = s.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            format!("\"{}\"", escaped)
+        }
+        other => other.to_string()
+    }
+}
+
+/// Read the current values of the prefs in the prefs.js of a profile
+pub fn read_current_prefs(profile_dir: &Path) -> Prefs {
+    match fs::read_to_string(profile_dir.join(PREFS_JS_FILENAME)) {
+        Ok(contents) => parse_prefs_js(&contents),
+        Err(_) => Prefs::new()
+    }
+}
+
+// prefs.js contains one `user_pref("name", value);` call per line
+fn parse_prefs_js(contents: &str) -> Prefs {
+    contents.lines()
+        .filter_map(|line| {
+            let args = line.trim().strip_prefix("user_pref(")?.strip_suffix(");")?;
+            let (name, rest) = parse_js_string(args.trim_start())?;
+            let value = rest.trim_start().strip_prefix(',')?.trim();
+            let value = match value {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ if value.starts_with('"') => match parse_js_string(value)? {
+                    (s, "") => Value::String(s),
+                    _ => return None
+                },
+                _ => Value::Number(value.parse::<i64>().ok()?.into())
+            };
+            Some((name, value))
+        })
+        .collect()
+}
+
+// Parse a string literal at the start of `input`, returns the string and the rest of the input
+fn parse_js_string(input: &str) -> Option<(String, &str)> {
+    let mut chars = input.strip_prefix('"')?.char_indices();
+    let mut result = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((result, &input[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                other => result.push(other)
+            },
+            c => result.push(c)
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn managed_block_keeps_user_prefs() {
+        let user_js = "user_pref(\"a\", 1);\n";
+        let mut prefs = Prefs::new();
+        prefs.insert("b".to_owned(), json!("quote \" and \\ backslash"));
+        prefs.insert("c".to_owned(), json!(true));
+
+        let with_block = replace_managed_block(user_js, &prefs);
+        assert!(with_block.starts_with(user_js));
+        let parsed = parse_prefs_js(&with_block);
+        assert_eq!(parsed.get("a"), Some(&json!(1)));
+        assert_eq!(parsed.get("b"), prefs.get("b"));
+        assert_eq!(parsed.get("c"), Some(&json!(true)));
+
+        // The block is replaced in place and removed when there are no prefs left
+        let appended = format!("{}user_pref(\"d\", false);\n", with_block);
+        prefs.remove("b");
+        let replaced = replace_managed_block(&appended, &prefs);
+        assert!(!replaced.contains("backslash"));
+        assert!(replaced.ends_with("user_pref(\"d\", false);\n"));
+        assert_eq!(replace_managed_block(&replaced, &Prefs::new()), "user_pref(\"a\", 1);\nuser_pref(\"d\", false);\n");
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io, env};
+use std::{io, env, fmt};
 use std::env::VarError;
 use cfg_if::cfg_if;
 use std::path::PathBuf;
@@ -6,6 +6,7 @@ use std::process::{exit, Child, Command, Stdio};
 use once_cell::sync::Lazy;
 use crate::state::AppState;
 use crate::profiles::ProfileEntry;
+use crate::cli::SUPERVISE_EPHEMERAL_COMMAND;
 
 cfg_if! {
     if #[cfg(target_family = "unix")] {
@@ -80,7 +81,11 @@ pub fn fork_browser_proc(app_state: &AppState, profile: &ProfileEntry, url: Opti
         Some(v) => v,
         None => match get_parent_proc_path() {
             Ok(v) => v,
-            Err(_) => return Err(ForkBrowserProcError::BinaryNotFound)
+            Err(GetParentProcError::LinuxParentExeDeleted(_)) => return Err(ForkBrowserProcError::BinaryDoesNotExist),
+            Err(e) => {
+                log::error!("Failed to find browser binary: {}", e);
+                return Err(ForkBrowserProcError::BinaryNotFound)
+            }
         }
     };
 
@@ -94,6 +99,21 @@ pub fn fork_browser_proc(app_state: &AppState, profile: &ProfileEntry, url: Opti
 
     log::trace!("Browser args: {:?}", browser_args);
 
+    spawn_detached_proc(parent_proc, browser_args)
+}
+
+/// Start the connector in the background to delete the specified ephemeral profile once the
+/// browser using it exits, see `ephemeral::supervise_ephemeral_profile`
+pub fn spawn_ephemeral_supervisor(profile_id: &str) -> Result<(), ForkBrowserProcError> {
+    let connector_binary = env::current_exe().map_err(ForkBrowserProcError::ProcessLaunchError)?;
+    spawn_detached_proc(&connector_binary, vec![
+        SUPERVISE_EPHEMERAL_COMMAND.to_owned(),
+        profile_id.to_owned()
+    ])
+}
+
+// Start a process that is not killed when we exit
+fn spawn_detached_proc(bin_path: &PathBuf, args: Vec<String>) -> Result<(), ForkBrowserProcError> {
     cfg_if! {
         if #[cfg(target_family = "unix")] {
             match unsafe { nix::unistd::fork() } {
@@ -111,7 +131,7 @@ pub fn fork_browser_proc(app_state: &AppState, profile: &ProfileEntry, url: Opti
                             libc::close(1);
                             libc::close(2);
                         }*/
-                        match spawn_browser_proc(&parent_proc, browser_args) {
+                        match spawn_browser_proc(bin_path, args) {
                             Ok(_) => 0,
                             Err(_) => 1
                         }
@@ -122,7 +142,7 @@ pub fn fork_browser_proc(app_state: &AppState, profile: &ProfileEntry, url: Opti
             }
         } else if #[cfg(target_family = "windows")] {
             // TODO Change app ID to separate on taskbar?
-            match spawn_browser_proc(&parent_proc, browser_args) {
+            match spawn_browser_proc(bin_path, args) {
                 Ok(_) => Ok(()),
                 Err(e) => Err(ForkBrowserProcError::ProcessLaunchError(e))
             }
@@ -166,16 +186,137 @@ pub enum GetParentProcError {
     LinuxOpenCurProcFailed(io::Error),
     LinuxFailedToParsePidString(String),
     LinuxCouldNotFindPPid,
-    LinuxResolveParentExeFailed(io::Error)
+    LinuxResolveParentExeFailed(io::Error),
+    /// The browser binary was replaced (e.g. by an upgrade) after the browser started
+    LinuxParentExeDeleted(PathBuf)
+}
+
+impl fmt::Display for GetParentProcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GetParentProcError::NoCrashReporterEnvVar(e) => write!(f, "crash reporter env var not available: {}", e),
+            GetParentProcError::LinuxOpenCurProcFailed(e) => write!(f, "failed to read process status: {}", e),
+            GetParentProcError::LinuxFailedToParsePidString(pid) => write!(f, "invalid parent pid {:?}", pid),
+            GetParentProcError::LinuxCouldNotFindPPid => write!(f, "parent pid not found in process status"),
+            GetParentProcError::LinuxResolveParentExeFailed(e) => write!(f, "failed to resolve the binary of the parent process: {}", e),
+            GetParentProcError::LinuxParentExeDeleted(path) => write!(f, "the browser binary {:?} was replaced after the browser started", path)
+        }
+    }
 }
 
 static PARENT_PROC: Lazy<Result<PathBuf, GetParentProcError>> = Lazy::new(|| {
     // Get browser binary by reading crash-reporter env var
-    env::var("MOZ_CRASHREPORTER_RESTART_ARG_0")
+    let from_env = env::var("MOZ_CRASHREPORTER_RESTART_ARG_0")
         .map(PathBuf::from)
-        .map_err(GetParentProcError::NoCrashReporterEnvVar)
+        .map_err(GetParentProcError::NoCrashReporterEnvVar);
+
+    // Distro builds often disable the crash reporter, look at our parent process instead
+    cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            from_env.or_else(|e| {
+                log::trace!("Crash reporter env var not available ({:?}), resolving parent process", e);
+                linux_proc::find_browser_ancestor_exe()
+            })
+        } else {
+            from_env
+        }
+    }
 });
 
 pub fn get_parent_proc_path() -> Result<&'static PathBuf, &'static GetParentProcError> {
     PARENT_PROC.as_ref()
 }
+
+/// Process id of the browser that started the connector, wrapper scripts are skipped
+#[cfg(target_os = "linux")]
+pub fn get_browser_pid() -> Result<i32, GetParentProcError> {
+    linux_proc::find_browser_ancestor().map(|(pid, _)| pid)
+}
+
+#[cfg(target_os = "linux")]
+mod linux_proc {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use super::GetParentProcError;
+
+    // The connector may be started through wrapper scripts (e.g. /usr/lib/firefox/firefox.sh or
+    // a script in the native messaging manifest), so shells are skipped until the browser is found
+    const SHELL_NAMES: &[&str] = &["sh", "bash", "dash", "zsh", "ksh", "mksh", "busybox", "env"];
+    // Give up if the browser is not found after this many ancestors
+    const MAX_ANCESTOR_DEPTH: usize = 8;
+    // The kernel appends this to the exe link if the binary was deleted or replaced
+    const DELETED_SUFFIX: &str = " (deleted)";
+
+    /// The pid and binary of the closest ancestor of the connector that is not a shell, the binary
+    /// may have the deleted suffix
+    pub fn find_browser_ancestor() -> Result<(i32, PathBuf), GetParentProcError> {
+        let mut pid = read_ppid("self")?;
+        for _ in 0..MAX_ANCESTOR_DEPTH {
+            let exe = fs::read_link(format!("/proc/{}/exe", pid))
+                .map_err(GetParentProcError::LinuxResolveParentExeFailed)?;
+            // A shell may have been updated while the wrapper script was running, only the
+            // browser binary itself must still exist
+            let exe_str = exe.to_string_lossy();
+            let original_exe = Path::new(exe_str.strip_suffix(DELETED_SUFFIX).unwrap_or(&exe_str));
+            if !is_shell(original_exe) {
+                return Ok((pid, exe))
+            }
+            log::trace!("Skipping wrapper process {} ({:?})", pid, exe);
+            pid = read_ppid(&pid.to_string())?;
+            // Reached init without finding anything but shells
+            if pid <= 1 {
+                break
+            }
+        }
+        Err(GetParentProcError::LinuxCouldNotFindPPid)
+    }
+
+    pub fn find_browser_ancestor_exe() -> Result<PathBuf, GetParentProcError> {
+        let (pid, exe) = find_browser_ancestor()?;
+        if let Some(original) = exe.to_string_lossy().strip_suffix(DELETED_SUFFIX) {
+            return Err(GetParentProcError::LinuxParentExeDeleted(PathBuf::from(original)));
+        }
+        log::trace!("Browser binary resolved from process {}: {:?}", pid, exe);
+        Ok(exe)
+    }
+
+    fn is_shell(exe: &Path) -> bool {
+        exe.file_name().is_some_and(|name| SHELL_NAMES.iter().any(|s| name == *s))
+    }
+
+    fn read_ppid(pid: &str) -> Result<i32, GetParentProcError> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid))
+            .map_err(GetParentProcError::LinuxOpenCurProcFailed)?;
+        parse_stat_ppid(&stat)
+    }
+
+    // The stat file looks like `pid (comm) state ppid ...`, comm may contain spaces and parentheses
+    // so the fields are counted from the last closing parenthesis
+    fn parse_stat_ppid(stat: &str) -> Result<i32, GetParentProcError> {
+        let ppid = stat.rfind(')')
+            .and_then(|i| stat[i + 1..].split_whitespace().nth(1))
+            .ok_or(GetParentProcError::LinuxCouldNotFindPPid)?;
+        ppid.parse().map_err(|_| GetParentProcError::LinuxFailedToParsePidString(ppid.to_owned()))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn ppid_is_parsed_from_stat() {
+            assert_eq!(parse_stat_ppid("1234 (firefox) S 1000 1234 1234 0 -1").unwrap(), 1000);
+            assert_eq!(parse_stat_ppid("1234 (Web Content (x)) S 42 1234").unwrap(), 42);
+            assert!(matches!(parse_stat_ppid("1234 (sh) S"), Err(GetParentProcError::LinuxCouldNotFindPPid)));
+            assert!(matches!(parse_stat_ppid("1234 (sh) S abc"), Err(GetParentProcError::LinuxFailedToParsePidString(_))));
+        }
+
+        #[test]
+        fn shells_are_recognized() {
+            assert!(is_shell(Path::new("/usr/bin/bash")));
+            assert!(is_shell(Path::new("/bin/sh")));
+            assert!(!is_shell(Path::new("/usr/lib/firefox/firefox")));
+            assert!(!is_shell(Path::new("/usr/bin/bash (deleted)")));
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+use crate::avatars::{build_avatar_path, list_avatars};
+use crate::profile_files::{is_portable_profile_file, visit_dir_filtered};
+use crate::profiles::ProfileEntry;
+
+// === PROFILE ARCHIVES ===
+
+// A profile archive is a gzipped tarball containing:
+// - manifest.json: the connector metadata of the profile (name, avatar, options)
+// - avatar/<ulid>.<ext>: the custom avatar of the profile, if it uses one
+// - profile/: the contents of the profile folder
+const ARCHIVE_FORMAT_VERSION: u32 = 1;
+const MANIFEST_PATH: &str = "manifest.json";
+const AVATAR_DIR: &str = "avatar";
+const PROFILE_DIR: &str = "profile";
+
+// Same limit as when adding avatars
+const MAX_AVATAR_SIZE: u64 = 500000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileArchiveManifest {
+    pub format_version: u32,
+    pub name: String,
+    pub avatar: Option<String>,
+    // File name of the custom avatar in the avatar folder of the archive
+    pub avatar_file: Option<String>,
+    #[serde(default)]
+    pub options: HashMap<String, Value>
+}
+
+#[derive(Debug)]
+pub struct ImportedProfileArchive {
+    pub avatar: Option<String>,
+    pub options: HashMap<String, Value>,
+    // Where the custom avatar of the archive was stored
+    pub avatar_path: Option<PathBuf>
+}
+
+#[derive(Debug)]
+pub enum ExportProfileError {
+    TargetExists,
+    WriteArchiveError(io::Error),
+    SerializeManifestError(serde_json::Error)
+}
+
+#[derive(Debug)]
+pub enum ImportProfileError {
+    ReadArchiveError(io::Error),
+    BadEntryPath(PathBuf),
+    MissingManifest,
+    BadManifestError(serde_json::Error),
+    UnsupportedFormatVersion(u32),
+    AvatarTooLarge,
+    WriteProfileError(io::Error),
+    WriteAvatarError(io::Error)
+}
+
+impl fmt::Display for ExportProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportProfileError::TargetExists => write!(f, "the target file already exists"),
+            ExportProfileError::WriteArchiveError(e) => write!(f, "failed to write the archive: {}", e),
+            ExportProfileError::SerializeManifestError(e) => write!(f, "failed to serialize the manifest: {}", e)
+        }
+    }
+}
+
+impl fmt::Display for ImportProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportProfileError::ReadArchiveError(e) => write!(f, "failed to read the archive: {}", e),
+            ImportProfileError::BadEntryPath(path) => write!(f, "archive entry {:?} points outside of the profile", path),
+            ImportProfileError::MissingManifest => write!(f, "the archive has no manifest"),
+            ImportProfileError::BadManifestError(e) => write!(f, "the manifest is invalid: {}", e),
+            ImportProfileError::UnsupportedFormatVersion(v) => write!(f, "unsupported archive format version {}", v),
+            ImportProfileError::AvatarTooLarge => write!(f, "the custom avatar is too large"),
+            ImportProfileError::WriteProfileError(e) => write!(f, "failed to write the profile folder: {}", e),
+            ImportProfileError::WriteAvatarError(e) => write!(f, "failed to store the custom avatar: {}", e)
+        }
+    }
+}
+
+/// Custom avatars are referenced by their ULID, possibly behind a prefix (e.g. `custom:<ulid>`)
+fn custom_avatar_ulid(avatar: &str) -> Option<Ulid> {
+    avatar.rsplit(':')
+        .next()
+        .and_then(|id| Ulid::from_str(id).ok())
+}
+
+/// Package a profile folder and its metadata into an archive, the archive is removed if this fails
+pub fn export_profile(profile: &ProfileEntry,
+                      profile_dir: &Path,
+                      avatars_dir: &Path,
+                      target: &Path) -> Result<(), ExportProfileError> {
+    let avatar_path = profile.avatar.as_deref()
+        .and_then(custom_avatar_ulid)
+        .and_then(|ulid| list_avatars(avatars_dir).get(&ulid).cloned());
+
+    let manifest = ProfileArchiveManifest {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        name: profile.name.clone(),
+        avatar: profile.avatar.clone(),
+        avatar_file: avatar_path.as_ref()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned()),
+        options: profile.options.clone()
+    };
+
+    // Never overwrite an existing file, it is only removed again if we created it
+    let file = match OpenOptions::new().write(true).create_new(true).open(target) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(ExportProfileError::TargetExists),
+        Err(e) => return Err(ExportProfileError::WriteArchiveError(e))
+    };
+    let result = write_archive(&manifest, avatar_path.as_deref(), profile_dir, file);
+    if result.is_err() {
+        if let Err(e) = fs::remove_file(target) {
+            log::error!("Failed to clean up partially written archive: {:?}", e);
+        }
+    }
+    result
+}
+
+fn write_archive(manifest: &ProfileArchiveManifest,
+                 avatar_path: Option<&Path>,
+                 profile_dir: &Path,
+                 file: File) -> Result<(), ExportProfileError> {
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    builder.follow_symlinks(false);
+
+    let serialized_manifest = serde_json::to_vec_pretty(manifest)
+        .map_err(ExportProfileError::SerializeManifestError)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(serialized_manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_PATH, serialized_manifest.as_slice())
+        .map_err(ExportProfileError::WriteArchiveError)?;
+
+    if let (Some(avatar_path), Some(avatar_file)) = (avatar_path, &manifest.avatar_file) {
+        builder.append_path_with_name(avatar_path, Path::new(AVATAR_DIR).join(avatar_file))
+            .map_err(ExportProfileError::WriteArchiveError)?;
+    }
+
+    builder.append_dir(PROFILE_DIR, profile_dir)
+        .map_err(ExportProfileError::WriteArchiveError)?;
+    visit_dir_filtered(profile_dir, &is_portable_profile_file, &mut |relative_path, path, is_dir| {
+        let archive_path = Path::new(PROFILE_DIR).join(relative_path);
+        if is_dir {
+            builder.append_dir(archive_path, path)
+        } else {
+            builder.append_path_with_name(path, archive_path)
+        }
+    }).map_err(ExportProfileError::WriteArchiveError)?;
+
+    builder.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|file| file.sync_all())
+        .map_err(ExportProfileError::WriteArchiveError)
+}
+
+/// Read only the manifest of an archive, e.g. to check the name of the profile before unpacking it
+pub fn read_archive_manifest(archive: &Path) -> Result<ProfileArchiveManifest, ImportProfileError> {
+    let file = File::open(archive).map_err(ImportProfileError::ReadArchiveError)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    // The manifest is the first entry of archives we exported
+    for entry in archive.entries().map_err(ImportProfileError::ReadArchiveError)? {
+        let entry = entry.map_err(ImportProfileError::ReadArchiveError)?;
+        let path = entry.path().map_err(ImportProfileError::ReadArchiveError)?;
+        let path: PathBuf = path.components().filter(|c| matches!(c, Component::Normal(_))).collect();
+        if path == Path::new(MANIFEST_PATH) {
+            let manifest: ProfileArchiveManifest = serde_json::from_reader(entry)
+                .map_err(ImportProfileError::BadManifestError)?;
+            return check_manifest_version(manifest);
+        }
+    }
+    Err(ImportProfileError::MissingManifest)
+}
+
+fn check_manifest_version(manifest: ProfileArchiveManifest) -> Result<ProfileArchiveManifest, ImportProfileError> {
+    if manifest.format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(ImportProfileError::UnsupportedFormatVersion(manifest.format_version));
+    }
+    Ok(manifest)
+}
+
+/// Unpack an archive into a new profile folder and store its custom avatar under a new id.
+/// The profile folder is removed if this fails.
+pub fn import_profile(archive: &Path,
+                      profile_dir: &Path,
+                      avatars_dir: &Path) -> Result<ImportedProfileArchive, ImportProfileError> {
+    let result = unpack_archive(archive, profile_dir)
+        .and_then(|(manifest, avatar_data)| store_imported_avatar(manifest, avatar_data, avatars_dir));
+    if result.is_err() {
+        if let Err(e) = fs::remove_dir_all(profile_dir) {
+            log::error!("Failed to clean up partially imported profile: {:?}", e);
+        }
+    }
+    result
+}
+
+// File name and contents of the custom avatar in an archive
+type ArchivedAvatar = (String, Vec<u8>);
+
+fn unpack_archive(archive: &Path,
+                  profile_dir: &Path) -> Result<(ProfileArchiveManifest, Option<ArchivedAvatar>), ImportProfileError> {
+    let file = File::open(archive).map_err(ImportProfileError::ReadArchiveError)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    fs::create_dir_all(profile_dir).map_err(ImportProfileError::WriteProfileError)?;
+
+    let mut manifest: Option<ProfileArchiveManifest> = None;
+    let mut avatar_data: Option<ArchivedAvatar> = None;
+    for entry in archive.entries().map_err(ImportProfileError::ReadArchiveError)? {
+        let mut entry = entry.map_err(ImportProfileError::ReadArchiveError)?;
+        let path = entry.path().map_err(ImportProfileError::ReadArchiveError)?.into_owned();
+        // Never write outside of the profile folder
+        if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(ImportProfileError::BadEntryPath(path));
+        }
+        let path: PathBuf = path.components().filter(|c| matches!(c, Component::Normal(_))).collect();
+        let entry_type = entry.header().entry_type();
+
+        if path == Path::new(MANIFEST_PATH) {
+            manifest = Some(serde_json::from_reader(&mut entry)
+                .map_err(ImportProfileError::BadManifestError)?);
+        } else if let Ok(avatar_file) = path.strip_prefix(AVATAR_DIR) {
+            if !entry_type.is_file() {
+                continue
+            }
+            if entry.size() > MAX_AVATAR_SIZE {
+                return Err(ImportProfileError::AvatarTooLarge);
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).map_err(ImportProfileError::ReadArchiveError)?;
+            avatar_data = Some((avatar_file.to_string_lossy().into_owned(), data));
+        } else if let Ok(relative_path) = path.strip_prefix(PROFILE_DIR) {
+            let target = profile_dir.join(relative_path);
+            if entry_type.is_dir() {
+                fs::create_dir_all(&target).map_err(ImportProfileError::WriteProfileError)?;
+            } else if entry_type.is_file() {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(ImportProfileError::WriteProfileError)?;
+                }
+                entry.unpack(&target).map_err(ImportProfileError::WriteProfileError)?;
+            } else {
+                log::warn!("Skipping unsupported archive entry: {:?}", path);
+            }
+        } else {
+            log::warn!("Skipping unknown archive entry: {:?}", path);
+        }
+    }
+
+    let manifest = check_manifest_version(manifest.ok_or(ImportProfileError::MissingManifest)?)?;
+    Ok((manifest, avatar_data))
+}
+
+fn store_imported_avatar(manifest: ProfileArchiveManifest,
+                         avatar_data: Option<ArchivedAvatar>,
+                         avatars_dir: &Path) -> Result<ImportedProfileArchive, ImportProfileError> {
+    // Give the custom avatar a new id so it cannot clash with the avatars already on this machine
+    let custom_avatar = avatar_data.filter(|(file, _)| manifest.avatar_file.as_ref() == Some(file));
+    let mut avatar = manifest.avatar;
+    let mut avatar_path = None;
+    if let (Some(old_avatar), Some((file, data))) = (&avatar, custom_avatar) {
+        let file = Path::new(&file);
+        let old_ulid = file.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| Ulid::from_str(s).ok());
+        let extension = file.extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase());
+        if let (Some(old_ulid), Some(extension)) = (old_ulid, extension) {
+            let new_ulid = Ulid::new();
+            let new_avatar_path = build_avatar_path(avatars_dir, new_ulid, &extension);
+            fs::create_dir_all(avatars_dir)
+                .and_then(|_| fs::write(&new_avatar_path, data))
+                .map_err(ImportProfileError::WriteAvatarError)?;
+            avatar = Some(old_avatar.replace(&old_ulid.to_string(), &new_ulid.to_string()));
+            avatar_path = Some(new_avatar_path);
+        }
+    }
+
+    Ok(ImportedProfileArchive {
+        avatar,
+        options: manifest.options,
+        avatar_path
+    })
+}
+
+/// Remove the profile folder and custom avatar of an import that could not be registered
+pub fn discard_imported_profile(imported: &ImportedProfileArchive, profile_dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(profile_dir) {
+        log::error!("Failed to clean up imported profile: {:?}", e);
+    }
+    if let Some(avatar_path) = &imported.avatar_path {
+        if let Err(e) = fs::remove_file(avatar_path) {
+            log::error!("Failed to clean up imported avatar: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn profile_archive_round_trip() {
+        let root = TempDir::new("profile-archive-test");
+        let profile_dir = root.join("source");
+        let avatars_dir = root.join("avatars");
+        fs::create_dir_all(profile_dir.join("storage").join("default")).unwrap();
+        fs::create_dir_all(profile_dir.join("cache2")).unwrap();
+        fs::create_dir_all(&avatars_dir).unwrap();
+        fs::write(profile_dir.join("prefs.js"), "user_pref(\"a\", 1);").unwrap();
+        fs::write(profile_dir.join("storage").join("default").join("data"), [1, 2, 3]).unwrap();
+        fs::write(profile_dir.join("cache2").join("entry"), "cached").unwrap();
+        fs::write(profile_dir.join(".parentlock"), "").unwrap();
+        fs::write(profile_dir.join("places.sqlite-wal"), "").unwrap();
+
+        let avatar_ulid = Ulid::new();
+        fs::write(build_avatar_path(&avatars_dir, avatar_ulid, "png"), [9, 9]).unwrap();
+
+        let mut profile = ProfileEntry::new_relative("Work", Some(format!("custom:{}", avatar_ulid)), HashMap::new());
+        profile.options.insert("color".to_owned(), Value::from("blue"));
+
+        let archive = root.join("work.tar.gz");
+        export_profile(&profile, &profile_dir, &avatars_dir, &archive).unwrap();
+        // Existing files are never overwritten or removed
+        assert!(matches!(export_profile(&profile, &profile_dir, &avatars_dir, &archive), Err(ExportProfileError::TargetExists)));
+        assert!(archive.exists());
+
+        let imported_dir = root.join("imported");
+        let imported = import_profile(&archive, &imported_dir, &avatars_dir).unwrap();
+
+        assert_eq!(read_archive_manifest(&archive).unwrap().name, "Work");
+        assert_eq!(imported.options.get("color"), Some(&Value::from("blue")));
+        assert_eq!(fs::read(imported_dir.join("prefs.js")).unwrap(), b"user_pref(\"a\", 1);");
+        assert_eq!(fs::read(imported_dir.join("storage").join("default").join("data")).unwrap(), [1, 2, 3]);
+        assert!(!imported_dir.join("cache2").exists());
+        assert!(!imported_dir.join(".parentlock").exists());
+        assert!(!imported_dir.join("places.sqlite-wal").exists());
+
+        // The avatar is copied under a new id
+        let new_avatar_ulid = custom_avatar_ulid(imported.avatar.as_deref().unwrap()).unwrap();
+        assert_ne!(new_avatar_ulid, avatar_ulid);
+        assert_eq!(fs::read(build_avatar_path(&avatars_dir, new_avatar_ulid, "png")).unwrap(), [9, 9]);
+
+        // An import that cannot be registered leaves nothing behind
+        discard_imported_profile(&imported, &imported_dir);
+        assert!(!imported_dir.exists());
+        assert!(!build_avatar_path(&avatars_dir, new_avatar_ulid, "png").exists());
+        assert!(build_avatar_path(&avatars_dir, avatar_ulid, "png").exists());
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs;
+use std::path::Path;
+use cfg_if::cfg_if;
+use serde_json::Value;
+use crate::config::Config;
+use crate::prefs::read_current_prefs;
+use crate::profile_lock::{detect_profile_lock_state, ProfileLockState};
+use crate::profiles::{ProfileEntry, ProfilesIniState};
+
+// === PROFILE DETECTION ===
+
+// Used when the extension does not know which profile it is running in. The browser that started
+// the connector is our closest ancestor that is not a shell, so the most reliable sources are tried first:
+// 1. the `-profile`/`-P` arguments of the browser process
+// 2. the profile whose `lock` symlink points at the browser process
+// 3. the profiles whose `extensions.webextensions.uuids` pref contains the internal extension id
+// 4. the profiles with a storage folder for the internal extension id
+// Profiles that are copies of each other share the internal extension id, so the last two can
+// match several profiles. Only running profiles are kept in that case and the detection is
+// reported as ambiguous if that does not settle it.
+const WEBEXTENSION_UUIDS_PREF: &str = "extensions.webextensions.uuids";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileDetection {
+    Found(String),
+    /// Several profiles match and none of the methods can tell them apart
+    Ambiguous(Vec<String>),
+    NotFound
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DetectionMethod {
+    BrowserProcArgs,
+    BrowserProcLock,
+    WebExtensionUuidPref,
+    ExtensionStorage
+}
+
+const DETECTION_METHODS: &[DetectionMethod] = &[
+    DetectionMethod::BrowserProcArgs,
+    DetectionMethod::BrowserProcLock,
+    DetectionMethod::WebExtensionUuidPref,
+    DetectionMethod::ExtensionStorage
+];
+
+pub fn detect_current_profile(profiles: &ProfilesIniState, config: &Config, internal_extension_id: &str) -> ProfileDetection {
+    let mut ambiguous = None;
+    for method in DETECTION_METHODS {
+        let mut matches = find_matches(*method, profiles, config, internal_extension_id);
+        if matches.len() > 1 {
+            matches.retain(|p| detect_profile_lock_state(&p.full_path(config), config) == ProfileLockState::Running);
+        }
+        match matches.as_slice() {
+            [] => continue,
+            [profile] => {
+                log::trace!("Profile ID determined using {:?}: {}", method, profile.id);
+                return ProfileDetection::Found(profile.id.clone())
+            }
+            _ => {
+                let ids: Vec<String> = matches.iter().map(|p| p.id.clone()).collect();
+                log::warn!("Multiple profiles found using {:?}: {:?}", method, ids);
+                ambiguous.get_or_insert(ids);
+            }
+        }
+    }
+
+    ambiguous.map_or(ProfileDetection::NotFound, ProfileDetection::Ambiguous)
+}
+
+fn find_matches<'a>(method: DetectionMethod,
+                    profiles: &'a ProfilesIniState,
+                    config: &Config,
+                    internal_extension_id: &str) -> Vec<&'a ProfileEntry> {
+    match method {
+        DetectionMethod::BrowserProcArgs => browser_proc_arg_matches(profiles, config),
+        DetectionMethod::BrowserProcLock => browser_proc_lock_matches(profiles, config),
+        DetectionMethod::WebExtensionUuidPref => profiles.profile_entries.iter()
+            .filter(|p| has_webextension_uuid(&p.full_path(config), internal_extension_id))
+            .collect(),
+        DetectionMethod::ExtensionStorage => profiles.profile_entries.iter()
+            .filter(|p| has_extension_storage(&p.full_path(config), internal_extension_id))
+            .collect()
+    }
+}
+
+// The pref maps addon ids to their internal ids, it is stored as a JSON string
+fn has_webextension_uuid(profile_dir: &Path, internal_extension_id: &str) -> bool {
+    read_current_prefs(profile_dir).get(WEBEXTENSION_UUIDS_PREF)
+        .and_then(Value::as_str)
+        .and_then(|uuids| serde_json::from_str::<Value>(uuids).ok())
+        .and_then(|uuids| uuids.as_object()
+            .map(|uuids| uuids.values().any(|uuid| uuid.as_str() == Some(internal_extension_id))))
+        .unwrap_or(false)
+}
+
+fn has_extension_storage(profile_dir: &Path, internal_extension_id: &str) -> bool {
+    let prefix = format!("moz-extension+++{}", internal_extension_id);
+    match fs::read_dir(profile_dir.join("storage").join("default")) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .any(|e| e.file_name().to_string_lossy().starts_with(&prefix)),
+        Err(_) => false
+    }
+}
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        use nix::unistd::Pid;
+        use crate::process::get_browser_pid;
+        use crate::profile_lock::lock_symlink_pid;
+
+        // The connector may be started through wrapper scripts so the browser is not necessarily
+        // our parent process
+        fn browser_pid() -> Option<Pid> {
+            match get_browser_pid() {
+                Ok(pid) => Some(Pid::from_raw(pid)),
+                Err(e) => {
+                    log::warn!("Failed to find browser process: {}", e);
+                    None
+                }
+            }
+        }
+
+        fn browser_proc_arg_matches<'a>(profiles: &'a ProfilesIniState, config: &Config) -> Vec<&'a ProfileEntry> {
+            let pid = match browser_pid() {
+                Some(pid) => pid,
+                None => return Vec::new()
+            };
+            let cmdline = match fs::read(format!("/proc/{}/cmdline", pid)) {
+                Ok(c) => c,
+                Err(e) => {
+                    log::warn!("Failed to read command line of browser process: {:?}", e);
+                    return Vec::new()
+                }
+            };
+            let args: Vec<String> = cmdline.split(|b| *b == 0)
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+            match find_profile_arg(&args) {
+                Some(arg) => profile_arg_matches(profiles, config, &arg),
+                None => Vec::new()
+            }
+        }
+
+        fn browser_proc_lock_matches<'a>(profiles: &'a ProfilesIniState, config: &Config) -> Vec<&'a ProfileEntry> {
+            let pid = match browser_pid() {
+                Some(pid) => pid,
+                None => return Vec::new()
+            };
+            profiles.profile_entries.iter()
+                .filter(|p| lock_symlink_pid(&p.full_path(config)) == Some(pid))
+                .collect()
+        }
+
+        // Find the value of `-profile <path>` or `-P <name>` in the arguments of a browser, the
+        // browser does not care about the case of the flags
+        fn find_profile_arg(args: &[String]) -> Option<ProfileArg> {
+            args.windows(2).find_map(|pair| match pair[0].to_ascii_lowercase().as_str() {
+                "-profile" | "--profile" => Some(ProfileArg::Path(pair[1].clone())),
+                "-p" => Some(ProfileArg::Name(pair[1].clone())),
+                _ => None
+            })
+        }
+
+        #[derive(Debug, PartialEq, Eq)]
+        enum ProfileArg {
+            Path(String),
+            Name(String)
+        }
+
+        fn profile_arg_matches<'a>(profiles: &'a ProfilesIniState, config: &Config, arg: &ProfileArg) -> Vec<&'a ProfileEntry> {
+            match arg {
+                ProfileArg::Path(path) => {
+                    let path = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+                    profiles.profile_entries.iter()
+                        .filter(|p| {
+                            let full_path = p.full_path(config);
+                            fs::canonicalize(&full_path).unwrap_or(full_path) == path
+                        })
+                        .collect()
+                }
+                ProfileArg::Name(name) => profiles.profile_entries.iter()
+                    .filter(|p| &p.name == name)
+                    .collect()
+            }
+        }
+    } else {
+        fn browser_proc_arg_matches<'a>(_profiles: &'a ProfilesIniState, _config: &Config) -> Vec<&'a ProfileEntry> {
+            Vec::new()
+        }
+
+        fn browser_proc_lock_matches<'a>(_profiles: &'a ProfilesIniState, _config: &Config) -> Vec<&'a ProfileEntry> {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_arg_is_found_in_browser_args() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(find_profile_arg(&args(&["firefox", "-profile", "/tmp/p", "--new-tab"])), Some(ProfileArg::Path("/tmp/p".to_owned())));
+        assert_eq!(find_profile_arg(&args(&["firefox", "-P", "Work"])), Some(ProfileArg::Name("Work".to_owned())));
+        assert_eq!(find_profile_arg(&args(&["firefox", "-p", "Work"])), Some(ProfileArg::Name("Work".to_owned())));
+        assert_eq!(find_profile_arg(&args(&["firefox", "-Profile", "/tmp/p"])), Some(ProfileArg::Path("/tmp/p".to_owned())));
+        assert_eq!(find_profile_arg(&args(&["firefox", "--new-tab", "-P"])), None);
+        assert_eq!(find_profile_arg(&args(&["firefox"])), None);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::ephemeral::EPHEMERAL_MARKER_FILENAME;
+use crate::profile_identity::PROFILE_ID_MARKER_FILENAME;
+
+// === PROFILE FILES ===
+
+// Files that belong to a running browser instance and must never be copied
+const LOCK_FILES: &[&str] = &["lock", ".parentlock", "parent.lock"];
+// Folders that the browser can rebuild by itself
+const CACHE_DIRS: &[&str] = &["cache2", "startupCache", "shader-cache", "crashes", "minidumps"];
+
+/// Whether the file is needed in a copy of a profile
+pub fn is_portable_profile_file(relative_path: &Path) -> bool {
+    let file_name = match relative_path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        None => return true
+    };
+    // Only skip caches in the root of the profile
+    let is_root = relative_path.parent().is_none_or(|p| p.as_os_str().is_empty());
+
+    // Copies must not share the id of the original profile and are never ephemeral
+    !(LOCK_FILES.contains(&file_name)
+        || (is_root && (file_name == PROFILE_ID_MARKER_FILENAME || file_name == EPHEMERAL_MARKER_FILENAME))
+        || file_name.ends_with("-wal")
+        || (is_root && CACHE_DIRS.contains(&file_name)))
+}
+
+/// Recursively copy a folder, skipping symlinks and files rejected by the filter.
+/// The filter is passed the path of each file relative to `src`.
+pub fn copy_dir_filtered(src: &Path, dst: &Path, filter: &dyn Fn(&Path) -> bool) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    visit_dir_filtered(src, filter, &mut |relative_path, path, is_dir| {
+        let target = dst.join(relative_path);
+        if is_dir {
+            fs::create_dir_all(target)
+        } else {
+            fs::copy(path, target).map(|_| ())
+        }
+    })
+}
+
+/// Recursively copy a folder with all of its contents. Fails if the folder contains a symlink
+/// instead of skipping it, so that callers can safely delete the original afterwards.
+pub fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), target)?;
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot copy symlink {:?}", entry.path())));
+        }
+    }
+    Ok(())
+}
+
+/// Move a folder, falling back to copying and deleting it if it cannot be renamed (e.g. because
+/// the destination is on another drive)
+pub fn move_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if let Err(e) = fs::rename(src, dst) {
+        log::trace!("Failed to rename {:?} to {:?}, copying instead: {:?}", src, dst, e);
+        if let Err(e) = copy_dir_all(src, dst) {
+            let _ = fs::remove_dir_all(dst);
+            return Err(e);
+        }
+        // The folder was copied so the move succeeded even if we cannot clean up the original
+        if let Err(e) = fs::remove_dir_all(src) {
+            log::warn!("Failed to delete {:?} after copying it to {:?}: {:?}", src, dst, e);
+        }
+    }
+    Ok(())
+}
+
+/// Recursively walk a folder, parents are visited before their contents. Symlinks and files
+/// rejected by the filter are skipped. The filter and the visitor are passed the path of each
+/// file relative to `src`, the visitor is also passed the full path and whether it is a folder.
+pub fn visit_dir_filtered(src: &Path,
+                          filter: &dyn Fn(&Path) -> bool,
+                          visitor: &mut dyn FnMut(&Path, &Path, bool) -> io::Result<()>) -> io::Result<()> {
+    visit_dir_filtered_rec(src, Path::new(""), filter, visitor)
+}
+
+fn visit_dir_filtered_rec(dir: &Path,
+                          relative_path: &Path,
+                          filter: &dyn Fn(&Path) -> bool,
+                          visitor: &mut dyn FnMut(&Path, &Path, bool) -> io::Result<()>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let entry_relative_path = relative_path.join(entry.file_name());
+        if !filter(&entry_relative_path) {
+            log::trace!("Skipping {:?} in {:?}", entry_relative_path, dir);
+            continue
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            visitor(&entry_relative_path, &entry.path(), true)?;
+            visit_dir_filtered_rec(&entry.path(), &entry_relative_path, filter, visitor)?;
+        } else if file_type.is_file() {
+            visitor(&entry_relative_path, &entry.path(), false)?;
+        } else {
+            log::trace!("Skipping symlink {:?} in {:?}", entry_relative_path, dir);
+        }
+    }
+    Ok(())
+}
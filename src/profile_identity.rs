@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::storage::write_file_atomic;
+
+// === PROFILE IDENTITY ===
+
+// Profile ids used to be calculated from the profile path (see `calc_profile_id`) so they changed
+// whenever a profile was moved. Profiles now carry their id in a marker file inside the profile
+// folder so the id follows the folder. Profiles whose folder cannot be written keep using the
+// path based id.
+//
+// The marker also records the folder it was written for. A marker whose folder is somewhere else
+// was either moved along with its folder, or copied if the original folder still has the same
+// marker. Copies and profiles without a marker keep using their path based id until they are
+// given a new id while the stores are locked, markers are only written while holding the lock so
+// reading the profile list never writes anything.
+pub const PROFILE_ID_MARKER_FILENAME: &str = ".profile-switcher-id";
+
+const MAX_PROFILE_ID_LEN: usize = 128;
+
+fn is_valid_profile_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_PROFILE_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileIdMarker {
+    Missing,
+    /// The marker was written for this folder
+    Owned(String),
+    /// The marker was written for another folder that no longer has it, or before markers
+    /// recorded their folder
+    Moved(String),
+    /// The folder the marker was written for still has it
+    Copied
+}
+
+// The marker contains the id and the folder it was written for, older markers only contain the id
+fn parse_marker(profile_dir: &Path) -> Option<(String, Option<PathBuf>)> {
+    let marker = fs::read_to_string(profile_dir.join(PROFILE_ID_MARKER_FILENAME)).ok()?;
+    let mut lines = marker.lines();
+    let id = lines.next()?.trim();
+    if !is_valid_profile_id(id) {
+        return None
+    }
+    let owner = lines.next().map(str::trim).filter(|o| !o.is_empty()).map(PathBuf::from);
+    Some((id.to_owned(), owner))
+}
+
+fn canonical_dir(profile_dir: &Path) -> PathBuf {
+    fs::canonicalize(profile_dir).unwrap_or_else(|_| profile_dir.to_owned())
+}
+
+pub fn check_profile_id_marker(profile_dir: &Path) -> ProfileIdMarker {
+    let (id, owner) = match parse_marker(profile_dir) {
+        Some(m) => m,
+        None => return ProfileIdMarker::Missing
+    };
+    let owner = match owner {
+        Some(o) => o,
+        None => return ProfileIdMarker::Moved(id)
+    };
+    if owner == canonical_dir(profile_dir) {
+        return ProfileIdMarker::Owned(id)
+    }
+    match parse_marker(&owner) {
+        Some((owner_id, Some(owner_owner))) if owner_id == id && owner_owner == owner => ProfileIdMarker::Copied,
+        _ => ProfileIdMarker::Moved(id)
+    }
+}
+
+/// The id stored in the folder of a profile, unless the folder is a copy of another profile
+pub fn read_profile_id_marker(profile_dir: &Path) -> Option<String> {
+    match check_profile_id_marker(profile_dir) {
+        ProfileIdMarker::Owned(id) | ProfileIdMarker::Moved(id) => Some(id),
+        ProfileIdMarker::Missing | ProfileIdMarker::Copied => None
+    }
+}
+
+/// Write the id marker of a profile, replacing an existing marker. The store lock must be held.
+pub fn write_profile_id_marker(profile_dir: &Path, id: &str) -> io::Result<()> {
+    let marker = format!("{}\n{}\n", id, canonical_dir(profile_dir).to_string_lossy());
+    write_file_atomic(&profile_dir.join(PROFILE_ID_MARKER_FILENAME), marker.as_bytes())
+}
+
+/// Find the id of the profile stored in `profile_dir` without writing anything. Falls back to
+/// `path_id` if the folder has no marker yet, if it is a copy of another profile or if its id is
+/// `taken` by another profile.
+pub fn resolve_profile_id(profile_dir: &Path, path_id: &str, taken: &HashSet<String>) -> String {
+    match check_profile_id_marker(profile_dir) {
+        ProfileIdMarker::Owned(id) | ProfileIdMarker::Moved(id) if !taken.contains(&id) => id,
+        ProfileIdMarker::Missing => path_id.to_owned(),
+        _ => {
+            log::warn!("Profile {:?} shares its id with another profile, using path based id for now", profile_dir);
+            path_id.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn profile_ids_follow_folders() {
+        let root = TempDir::new("profile-identity-test");
+        let profile_dir = root.join("a");
+        fs::create_dir_all(&profile_dir).unwrap();
+
+        // Resolving an id never writes a marker
+        assert_eq!(resolve_profile_id(&profile_dir, "PATHID", &HashSet::new()), "PATHID");
+        assert_eq!(check_profile_id_marker(&profile_dir), ProfileIdMarker::Missing);
+
+        let id = "PROFILEID".to_owned();
+        write_profile_id_marker(&profile_dir, &id).unwrap();
+        assert_eq!(resolve_profile_id(&profile_dir, "PATHID", &HashSet::new()), id);
+        assert_eq!(resolve_profile_id(&profile_dir, "PATHID", &HashSet::from([id.clone()])), "PATHID");
+
+        // The id is kept when the folder moves
+        let moved_dir = root.join("b");
+        fs::rename(&profile_dir, &moved_dir).unwrap();
+        assert_eq!(check_profile_id_marker(&moved_dir), ProfileIdMarker::Moved(id.clone()));
+        assert_eq!(resolve_profile_id(&moved_dir, "OTHERPATHID", &HashSet::new()), id);
+        write_profile_id_marker(&moved_dir, &id).unwrap();
+        assert_eq!(check_profile_id_marker(&moved_dir), ProfileIdMarker::Owned(id.clone()));
+
+        // Copies are detected no matter which one is resolved first and keep their marker until
+        // they are given a new id
+        let copy_dir = root.join("c");
+        fs::create_dir_all(&copy_dir).unwrap();
+        fs::copy(moved_dir.join(PROFILE_ID_MARKER_FILENAME), copy_dir.join(PROFILE_ID_MARKER_FILENAME)).unwrap();
+        assert_eq!(check_profile_id_marker(&copy_dir), ProfileIdMarker::Copied);
+        assert_eq!(resolve_profile_id(&copy_dir, "COPYPATHID", &HashSet::new()), "COPYPATHID");
+        assert_eq!(resolve_profile_id(&moved_dir, "OTHERPATHID", &HashSet::new()), id);
+        assert_eq!(check_profile_id_marker(&copy_dir), ProfileIdMarker::Copied);
+
+        // Missing folders keep their path based id
+        assert_eq!(resolve_profile_id(&root.join("missing"), "PATHID", &HashSet::new()), "PATHID");
+    }
+}
@@ -0,0 +1,163 @@
+use std::path::Path;
+use cfg_if::cfg_if;
+use serde::Serialize;
+use crate::config::Config;
+
+// === PROFILE LOCK ===
+
+// Firefox locks a profile while it is running:
+// - Linux and macOS: `.parentlock` is locked with fcntl and (on Linux) `lock` is a symlink to `IP:+PID`
+// - Windows: `parent.lock` is kept open without sharing
+// The lock files are left behind when the browser exits, so we probe them instead of checking
+// whether they exist.
+
+/// SQLite journals that are only left behind if the browser did not shut down properly
+const UNCLEAN_SHUTDOWN_FILES: &[&str] = &["cookies.sqlite-wal", "webappsstore.sqlite-wal", "places.sqlite-wal"];
+
+// Browsers that are built from Firefox and keep the same process name conventions
+const BROWSER_PROCESS_NAMES: &[&str] = &["firefox", "librewolf", "waterfox", "floorp", "icecat"];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileLockState {
+    /// A browser is using the profile
+    Running,
+    /// The browser did not shut down properly (e.g. it crashed) and left its lock files behind
+    Stale,
+    /// The profile can be safely modified
+    Closed
+}
+
+pub fn detect_profile_lock_state(profile_path: &Path, config: &Config) -> ProfileLockState {
+    let state = if is_profile_locked(profile_path, config) {
+        ProfileLockState::Running
+    } else if has_lock_leftovers(profile_path) {
+        ProfileLockState::Stale
+    } else {
+        ProfileLockState::Closed
+    };
+    log::trace!("Profile {:?} lock state: {:?}", profile_path, state);
+    state
+}
+
+fn has_lock_leftovers(profile_path: &Path) -> bool {
+    // `symlink_metadata` also detects dangling symlinks
+    profile_path.join("lock").symlink_metadata().is_ok()
+        || UNCLEAN_SHUTDOWN_FILES.iter().any(|f| profile_path.join(f).exists())
+}
+
+cfg_if! {
+    if #[cfg(target_family = "unix")] {
+        use std::fs::{self, File};
+        use std::os::unix::io::AsRawFd;
+        use nix::errno::Errno;
+        use nix::fcntl::{fcntl, FcntlArg};
+        use nix::sys::signal::kill;
+        use nix::unistd::Pid;
+
+        fn is_profile_locked(profile_path: &Path, config: &Config) -> bool {
+            is_parentlock_held(profile_path) || lock_symlink_pid(profile_path)
+                .is_some_and(|pid| is_browser_process_alive(pid, config))
+        }
+
+        // Ask which process holds a write lock on .parentlock without taking the lock ourselves
+        fn is_parentlock_held(profile_path: &Path) -> bool {
+            let file = match File::open(profile_path.join(".parentlock")) {
+                Ok(f) => f,
+                Err(_) => return false
+            };
+            let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+            flock.l_type = libc::F_WRLCK as libc::c_short;
+            flock.l_whence = libc::SEEK_SET as libc::c_short;
+            match fcntl(file.as_raw_fd(), FcntlArg::F_GETLK(&mut flock)) {
+                Ok(_) => flock.l_type != libc::F_UNLCK as libc::c_short,
+                Err(e) => {
+                    log::warn!("Failed to probe lock on {:?}: {:?}", profile_path, e);
+                    false
+                }
+            }
+        }
+
+        // The symlink target looks like `127.0.1.1:+12345` (or `127.0.1.1:12345` in older versions)
+        pub fn lock_symlink_pid(profile_path: &Path) -> Option<Pid> {
+            let target = fs::read_link(profile_path.join("lock")).ok()?;
+            let target = target.to_str()?;
+            let pid = target.rsplit(':').next()?.trim_start_matches('+');
+            pid.parse().ok().map(Pid::from_raw)
+        }
+
+        fn is_browser_process_alive(pid: Pid, config: &Config) -> bool {
+            match kill(pid, None) {
+                // The process exists but we are not allowed to signal it
+                Err(Errno::EPERM) => true,
+                Err(_) => false,
+                Ok(()) => match process_name(pid) {
+                    Some(name) => is_browser_process_name(&name, config),
+                    // Assume the process is the browser if we cannot tell
+                    None => true
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        fn process_name(pid: Pid) -> Option<String> {
+            let proc_dir = Path::new("/proc").join(pid.to_string());
+            fs::read_link(proc_dir.join("exe")).ok()
+                .and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .or_else(|| fs::read_to_string(proc_dir.join("comm")).ok().map(|c| c.trim().to_owned()))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn process_name(_pid: Pid) -> Option<String> {
+            None
+        }
+    } else if #[cfg(target_family = "windows")] {
+        use std::fs::OpenOptions;
+
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+
+        // The browser keeps parent.lock open without allowing anyone else to open it
+        fn is_profile_locked(profile_path: &Path, _config: &Config) -> bool {
+            match OpenOptions::new().read(true).open(profile_path.join("parent.lock")) {
+                Err(e) => e.raw_os_error() == Some(ERROR_SHARING_VIOLATION),
+                Ok(_) => false
+            }
+        }
+    }
+}
+
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+fn is_browser_process_name(name: &str, config: &Config) -> bool {
+    let name = name.to_lowercase();
+    let configured_binary = config.browser_binary()
+        .and_then(|b| b.file_stem())
+        .map(|b| b.to_string_lossy().to_lowercase());
+    BROWSER_PROCESS_NAMES.iter().any(|b| is_name_variant(&name, b))
+        || configured_binary.is_some_and(|b| is_name_variant(&name, &b))
+}
+
+// Matches e.g. `firefox`, `firefox-bin` and `firefox-esr` but not `firefox_profile_switcher_connector`
+fn is_name_variant(name: &str, base_name: &str) -> bool {
+    match name.strip_prefix(base_name) {
+        Some(rest) => rest.is_empty() || rest.starts_with('-') || rest.starts_with('.'),
+        None => false
+    }
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn lock_symlink_of_other_process_is_stale() {
+        let profile_dir = TempDir::new("profile-lock-test");
+        let config = Config::default();
+        assert_eq!(detect_profile_lock_state(&profile_dir, &config), ProfileLockState::Closed);
+
+        // Our own process is alive but is not a browser
+        symlink(format!("127.0.1.1:+{}", std::process::id()), profile_dir.join("lock")).unwrap();
+        assert_eq!(lock_symlink_pid(&profile_dir), Some(Pid::from_raw(std::process::id() as i32)));
+        assert_eq!(detect_profile_lock_state(&profile_dir, &config), ProfileLockState::Stale);
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use crate::config::Config;
+use crate::profiles::ProfilesIniState;
+
+// === PROFILE SCAN ===
+
+// Files that every profile folder created by the browser contains
+const PROFILE_MARKER_FILES: &[&str] = &["prefs.js", "times.json"];
+// Firefox creates new profiles in this sub-folder on Windows and macOS
+const PROFILES_SUBDIR: &str = "Profiles";
+
+/// A profile folder in the browser profile dir that is not in profiles.ini
+#[derive(Serialize, Debug, Clone)]
+pub struct OrphanedProfileDir {
+    /// Path relative to the browser profile dir, in the format used by profiles.ini
+    pub path: String,
+    pub full_path: PathBuf,
+    /// Taken from the folder name (e.g. `abcd1234.work` becomes `work`)
+    pub suggested_name: String
+}
+
+/// A profile in profiles.ini whose folder does not exist
+#[derive(Serialize, Debug, Clone)]
+pub struct DanglingProfileEntry {
+    pub profile_id: String,
+    pub name: String,
+    pub full_path: PathBuf
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ProfileScan {
+    pub orphans: Vec<OrphanedProfileDir>,
+    pub dangling: Vec<DanglingProfileEntry>
+}
+
+pub fn scan_profiles(config: &Config, profiles: &ProfilesIniState) -> ProfileScan {
+    let known_paths: HashSet<PathBuf> = profiles.profile_entries.iter()
+        .map(|p| normalize_path(&p.full_path(config)))
+        .collect();
+
+    let browser_profile_dir = config.browser_profile_dir();
+    let mut orphans: Vec<OrphanedProfileDir> = list_profile_dirs(&browser_profile_dir, None)
+        .into_iter()
+        .chain(list_profile_dirs(&browser_profile_dir.join(PROFILES_SUBDIR), Some(PROFILES_SUBDIR)))
+        .filter(|o| !known_paths.contains(&normalize_path(&o.full_path)))
+        .collect();
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let dangling = profiles.profile_entries.iter()
+        .filter(|p| !p.full_path(config).is_dir())
+        .map(|p| DanglingProfileEntry {
+            profile_id: p.id.clone(),
+            name: p.name.clone(),
+            full_path: p.full_path(config)
+        })
+        .collect();
+
+    ProfileScan { orphans, dangling }
+}
+
+/// List the profile folders directly inside `dir`, `prefix` is prepended to their relative paths
+fn list_profile_dirs(dir: &Path, prefix: Option<&str>) -> Vec<OrphanedProfileDir> {
+    let entries = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_) => return Vec::new()
+    };
+
+    entries.filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter(|e| PROFILE_MARKER_FILES.iter().any(|f| e.path().join(f).is_file()))
+        .filter_map(|e| {
+            let dir_name = e.file_name().into_string().ok()?;
+            let suggested_name = match dir_name.split_once('.') {
+                Some((_, name)) if !name.is_empty() => name.to_owned(),
+                _ => dir_name.clone()
+            };
+            Some(OrphanedProfileDir {
+                // profiles.ini always uses forward slashes
+                path: match prefix {
+                    Some(prefix) => format!("{}/{}", prefix, dir_name),
+                    None => dir_name
+                },
+                full_path: e.path(),
+                suggested_name
+            })
+        })
+        .collect()
+}
+
+// Profiles may be referred to through different but equivalent paths
+fn normalize_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -1,14 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde_json::Value;
 use serde::{Deserialize, Serialize};
 use crate::config::Config;
 use std::path::{PathBuf, Path};
-use ini::{EscapePolicy, Ini, ParseOption};
+use ini::{EscapePolicy, Ini, ParseOption, Properties, SectionEntry};
+use indexmap::IndexMap;
 use std::io;
+use std::fs;
 use std::fs::OpenOptions;
-use crate::storage::{avatar_data_path, options_data_path, order_data_path};
+use crate::storage::{avatar_data_path, bump_state_revision, options_data_path, write_file_atomic, FileSnapshot};
+use crate::state::AppState;
+use crate::backups::backup_profiles_ini;
+use crate::profile_identity::{check_profile_id_marker, read_profile_id_marker, resolve_profile_id, write_profile_id_marker, ProfileIdMarker};
 use ring::digest::{Context, SHA256};
 use data_encoding::HEXUPPER;
+use ulid::Ulid;
 
 // === PROFILE ===
 pub struct ProfileEntry {
@@ -18,39 +24,108 @@ pub struct ProfileEntry {
     pub path: String,
     pub default: bool,
     pub avatar: Option<String>,
-    pub options: HashMap<String, Value>
+    pub options: HashMap<String, Value>,
+    /// Incremented every time the profile is edited, used to detect conflicting edits
+    pub revision: u64,
+    /// Keys in the profile's section that we do not use (e.g. `StoreID`), written back as-is
+    pub extra_keys: IndexMap<String, String>,
+    /// Order of the keys in the profile's section, new keys are written after these
+    pub key_order: Vec<String>
 }
 
 impl ProfileEntry {
     pub fn full_path(&self, config: &Config) -> PathBuf {
-        if self.is_relative {
-            let mut result = config.browser_profile_dir();
-            result.push(&self.path);
-            result
-        } else {
-            PathBuf::from(&self.path)
+        profile_full_path(&config.browser_profile_dir(), &self.path, self.is_relative)
+    }
+
+    /// Build a new profile stored in a new folder in the browser profile dir, the folder is not created
+    pub fn new_relative(name: &str, avatar: Option<String>, options: HashMap<String, Value>) -> ProfileEntry {
+        let new_profile_path = "profile-".to_owned() + &Ulid::new().to_string();
+        Self::new(name, new_profile_path, true, avatar, options)
+    }
+
+    /// The id is calculated from the path until the profile is registered with `assign_profile_id`
+    pub fn new(name: &str, path: String, is_relative: bool, avatar: Option<String>, options: HashMap<String, Value>) -> ProfileEntry {
+        ProfileEntry {
+            id: calc_profile_id(&path, is_relative),
+            name: name.trim().to_owned(),
+            is_relative,
+            path,
+            default: false,
+            avatar,
+            options,
+            revision: 0,
+            extra_keys: IndexMap::new(),
+            key_order: Vec::new()
         }
     }
 }
 
 pub struct ProfilesIniState {
     backing_ini: Ini,
-    pub profile_entries: Vec<ProfileEntry>
+    pub profile_entries: Vec<ProfileEntry>,
+    // Original section name of each profile, `backing_ini` holds an empty placeholder section
+    // for each of them so they can be written back in their original position
+    profile_sections: HashMap<String, String>,
+    // Profile sections that could not be parsed, they are kept as-is in `backing_ini`
+    quarantined_sections: Vec<QuarantinedSection>,
+    // Metadata of the quarantined profiles, kept so it is not lost when we rewrite the stores
+    retained_avatar_data: AvatarData,
+    retained_options_data: OptionsData,
+    // Path based ids of profiles whose id is stored in their folder, mapped to that id
+    path_ids: HashMap<String, String>,
+    // Whether some metadata is still stored under the path based ids
+    has_path_id_metadata: bool
+}
+
+fn profile_full_path(browser_profile_dir: &Path, path: &str, is_relative: bool) -> PathBuf {
+    if is_relative {
+        browser_profile_dir.join(path)
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+struct QuarantinedSection {
+    name: String,
+    missing_keys: Vec<&'static str>
+}
+
+/// Problems found in profiles.ini that the user may want to know about
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum ProfilesIniWarning {
+    /// The section is missing required keys, it was skipped but will be preserved on rewrite
+    QuarantinedSection { section: String, missing_keys: Vec<&'static str> },
+    MultipleDefaults { profile_ids: Vec<String> },
+    DuplicateName { name: String, profile_ids: Vec<String> },
+    MissingDirectory { profile_id: String, path: PathBuf },
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// Changes made while repairing profiles.ini
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum ProfilesIniRepair {
+    RestoredSection { section: String, profile_id: String },
+    UnsetDefault { profile_id: String },
+    Renamed { profile_id: String, new_name: String },
+    CreatedDirectory { profile_id: String, path: PathBuf },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 struct AvatarData {
     avatars: HashMap<String, String>
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 struct OptionsData {
-    options: HashMap<String, HashMap<String, Value>>
+    options: HashMap<String, HashMap<String, Value>>,
+    #[serde(default)]
+    revisions: HashMap<String, u64>
 }
 
 #[derive(Debug)]
 pub enum ReadProfilesError {
-    BadIniFormat,
     IniError(ini::Error),
     AvatarStoreError(io::Error),
     BadAvatarStoreFormat(serde_json::Error),
@@ -94,124 +169,495 @@ pub fn read_profiles(config: &Config, config_dir: &Path) -> Result<ProfilesIniSt
             OptionsData::default()
         });
 
+    Ok(parse_profiles_ini(&profiles_conf, &avatar_data, &options_data, Some(&config.browser_profile_dir())))
+}
+
+/// Profile ids are only read from (and assigned to) the profile folders if `browser_profile_dir` is set,
+/// otherwise the path based ids are used
+fn parse_profiles_ini(profiles_conf: &Ini,
+                      avatar_data: &AvatarData,
+                      options_data: &OptionsData,
+                      browser_profile_dir: Option<&Path>) -> ProfilesIniState {
     let mut state = ProfilesIniState {
         backing_ini: Ini::new(),
         profile_entries: Vec::new(),
+        profile_sections: HashMap::new(),
+        quarantined_sections: Vec::new(),
+        retained_avatar_data: AvatarData::default(),
+        retained_options_data: OptionsData::default(),
+        path_ids: HashMap::new(),
+        has_path_id_metadata: false
     };
 
-    for (sec, prop) in &profiles_conf {
-        if sec.is_none() || !sec.unwrap().starts_with("Profile") {
-            // Save non-profile keys in new INI file
-            let mut section_setter = &mut state.backing_ini.with_section(sec);
-            for (key, value) in prop.iter() {
-                section_setter = section_setter.set(key, value);
+    for (sec, prop) in profiles_conf {
+        let profile_section = match sec {
+            Some(sec) if sec.starts_with("Profile") => Some(sec),
+            _ => None
+        };
+        let parsed = profile_section.map(|sec| (sec, parse_profile_section(prop)));
+
+        match parsed {
+            Some((sec, Ok(parsed))) => {
+                let id = browser_profile_dir.map(|dir| state.resolve_id(dir, &parsed.path, parsed.is_relative));
+                let entry = state.build_profile_entry(parsed, id, avatar_data, options_data);
+                state.backing_ini.entry(Some(sec.to_owned())).or_insert_with(Properties::new);
+                state.profile_sections.insert(entry.id.clone(), sec.to_owned());
+                state.profile_entries.push(entry);
             }
-        } else {
-            // Parse profile keys
-            let mut profile_name = None::<String>;
-            let mut profile_is_relative = None::<bool>;
-            let mut profile_path = None::<String>;
-            let mut profile_default = false;
-
-            for (key, value) in prop.iter() {
-                match key {
-                    "Name" => profile_name = Some(value.to_owned()),
-                    "IsRelative" => profile_is_relative = Some(value == "1"),
-                    "Path" => profile_path = Some(value.to_owned()),
-                    "Default" => profile_default = value == "1",
-                    _ => {}
+            _ => {
+                if let Some((sec, Err(missing_keys))) = parsed {
+                    log::warn!("Profile section {} is missing keys {:?}, skipping it", sec, missing_keys);
+
+                    // The profile ID depends on IsRelative so keep metadata for all possible IDs
+                    if let Some(path) = prop.get("Path") {
+                        let mut ids = vec![calc_profile_id(path, true), calc_profile_id(path, false)];
+                        if let Some(dir) = browser_profile_dir {
+                            ids.extend([true, false].iter()
+                                .filter_map(|is_relative| read_profile_id_marker(&profile_full_path(dir, path, *is_relative))));
+                        }
+                        for id in ids {
+                            retain_metadata(&id, avatar_data, options_data,
+                                            &mut state.retained_avatar_data, &mut state.retained_options_data);
+                        }
+                    }
+
+                    state.quarantined_sections.push(QuarantinedSection {
+                        name: sec.to_owned(),
+                        missing_keys
+                    });
+                }
+
+                // Save non-profile (and quarantined profile) sections in new INI file
+                match state.backing_ini.entry(sec.map(str::to_owned)) {
+                    SectionEntry::Occupied(mut e) => e.append(prop.clone()),
+                    SectionEntry::Vacant(e) => { e.insert(prop.clone()); }
                 }
             }
+        }
+    }
+
+    state
+}
+
+struct ParsedProfileSection {
+    name: String,
+    is_relative: bool,
+    path: String,
+    default: bool,
+    extra_keys: IndexMap<String, String>,
+    key_order: Vec<String>
+}
 
-            if profile_name.is_none() || profile_path.is_none() || profile_is_relative.is_none() {
-                return Err(ReadProfilesError::BadIniFormat)
+// Parse profile keys, returns the missing keys if any required key is missing
+fn parse_profile_section(prop: &Properties) -> Result<ParsedProfileSection, Vec<&'static str>> {
+    let mut profile_name = None::<String>;
+    let mut profile_is_relative = None::<bool>;
+    let mut profile_path = None::<String>;
+    let mut profile_default = false;
+    let mut extra_keys = IndexMap::new();
+    let key_order = section_key_order(prop);
+
+    for (key, value) in prop.iter() {
+        match key {
+            "Name" => profile_name = Some(value.to_owned()),
+            "IsRelative" => profile_is_relative = Some(value == "1"),
+            "Path" => profile_path = Some(value.to_owned()),
+            "Default" => profile_default = value == "1",
+            _ => { extra_keys.insert(key.to_owned(), value.to_owned()); }
+        }
+    }
+
+    match (profile_name, profile_is_relative, profile_path) {
+        (Some(name), Some(is_relative), Some(path)) => Ok(ParsedProfileSection {
+            name,
+            is_relative,
+            path,
+            default: profile_default,
+            extra_keys,
+            key_order
+        }),
+        (name, is_relative, path) => Err([
+            ("Name", name.is_none()),
+            ("IsRelative", is_relative.is_none()),
+            ("Path", path.is_none())
+        ].iter().filter(|k| k.1).map(|k| k.0).collect())
+    }
+}
+
+fn section_key_order(prop: &Properties) -> Vec<String> {
+    let mut key_order: Vec<String> = Vec::new();
+    for (key, _) in prop.iter() {
+        if !key_order.iter().any(|k| k == key) {
+            key_order.push(key.to_owned());
+        }
+    }
+    key_order
+}
+
+// Metadata of profiles was stored under their path based id before profiles had their own ids
+fn lookup_metadata<'a, T>(data: &'a HashMap<String, T>, profile_id: &str, path_id: &str) -> Option<&'a T> {
+    data.get(profile_id).or_else(|| data.get(path_id))
+}
+
+fn retain_metadata(id: &str,
+                   avatar_data: &AvatarData,
+                   options_data: &OptionsData,
+                   retained_avatar_data: &mut AvatarData,
+                   retained_options_data: &mut OptionsData) {
+    if let Some(avatar) = avatar_data.avatars.get(id) {
+        retained_avatar_data.avatars.insert(id.to_owned(), avatar.clone());
+    }
+    if let Some(options) = options_data.options.get(id) {
+        retained_options_data.options.insert(id.to_owned(), options.clone());
+    }
+    if let Some(revision) = options_data.revisions.get(id) {
+        retained_options_data.revisions.insert(id.to_owned(), *revision);
+    }
+}
+
+impl ProfilesIniState {
+    // Read the id of a profile from its folder, the id must not be used by any profile we already parsed
+    fn resolve_id(&self, browser_profile_dir: &Path, path: &str, is_relative: bool) -> String {
+        let taken: HashSet<String> = self.profile_entries.iter().map(|p| p.id.clone()).collect();
+        let path_id = calc_profile_id(path, is_relative);
+        resolve_profile_id(&profile_full_path(browser_profile_dir, path, is_relative), &path_id, &taken)
+    }
+
+    /// `profile_id` defaults to the path based id
+    fn build_profile_entry(&mut self,
+                           parsed: ParsedProfileSection,
+                           profile_id: Option<String>,
+                           avatar_data: &AvatarData,
+                           options_data: &OptionsData) -> ProfileEntry {
+        let path_id = calc_profile_id(&parsed.path, parsed.is_relative);
+        let profile_id = profile_id.unwrap_or_else(|| path_id.clone());
+        if profile_id != path_id {
+            self.has_path_id_metadata |= avatar_data.avatars.contains_key(&path_id)
+                || options_data.options.contains_key(&path_id)
+                || options_data.revisions.contains_key(&path_id);
+            self.path_ids.insert(path_id.clone(), profile_id.clone());
+        }
+
+        let avatar = lookup_metadata(&avatar_data.avatars, &profile_id, &path_id).map(String::clone);
+        let options = lookup_metadata(&options_data.options, &profile_id, &path_id)
+            .map(HashMap::clone)
+            .unwrap_or_else(HashMap::new);
+        let revision = lookup_metadata(&options_data.revisions, &profile_id, &path_id)
+            .copied()
+            .unwrap_or(0);
+
+        ProfileEntry {
+            id: profile_id,
+            name: parsed.name,
+            is_relative: parsed.is_relative,
+            path: parsed.path,
+            default: parsed.default,
+            avatar,
+            options,
+            revision,
+            extra_keys: parsed.extra_keys,
+            key_order: parsed.key_order
+        }
+    }
+
+    /// Give a profile that is about to be added its own id and store it in the profile folder, the
+    /// store lock must be held and the profile folder must already exist. Profiles that already
+    /// have an id (e.g. restored from the trash) keep it.
+    pub fn assign_profile_id(&self, profile: &mut ProfileEntry, config: &Config) {
+        let browser_profile_dir = config.browser_profile_dir();
+        let profile_dir = profile_full_path(&browser_profile_dir, &profile.path, profile.is_relative);
+        let path_id = calc_profile_id(&profile.path, profile.is_relative);
+        let id = self.resolve_id(&browser_profile_dir, &profile.path, profile.is_relative);
+        if id != path_id {
+            // The marker may still name the folder the profile was stored in before
+            if let Err(e) = write_profile_id_marker(&profile_dir, &id) {
+                log::warn!("Failed to update id marker of profile {:?}: {:?}", profile_dir, e);
+            }
+            profile.id = id;
+            return
+        }
+
+        let new_id = Ulid::new().to_string();
+        profile.id = match write_profile_id_marker(&profile_dir, &new_id) {
+            Ok(()) => {
+                log::trace!("Assigned id {} to profile {:?}", new_id, profile_dir);
+                new_id
             }
+            Err(e) => {
+                log::warn!("Failed to assign id to profile {:?}, using path based id: {:?}", profile_dir, e);
+                path_id
+            }
+        };
+    }
+
+    /// Give copied profiles and profiles without an id marker their own id and record the current
+    /// folder in the id marker of moved profiles, the store lock must be held. Metadata of profiles that got a new id is moved to
+    /// it the next time the stores are written.
+    pub fn repair_profile_ids(&mut self, config: &Config) {
+        let browser_profile_dir = config.browser_profile_dir();
+        for entry in self.profile_entries.iter_mut() {
+            let profile_dir = profile_full_path(&browser_profile_dir, &entry.path, entry.is_relative);
+            let path_id = calc_profile_id(&entry.path, entry.is_relative);
+            match check_profile_id_marker(&profile_dir) {
+                ProfileIdMarker::Moved(id) if id == entry.id => {
+                    if let Err(e) = write_profile_id_marker(&profile_dir, &id) {
+                        log::warn!("Failed to update id marker of moved profile {:?}: {:?}", profile_dir, e);
+                    }
+                }
+                ProfileIdMarker::Copied | ProfileIdMarker::Missing if entry.id == path_id && profile_dir.is_dir() => {
+                    let new_id = Ulid::new().to_string();
+                    if let Err(e) = write_profile_id_marker(&profile_dir, &new_id) {
+                        log::warn!("Failed to assign id to profile {:?}: {:?}", profile_dir, e);
+                        continue
+                    }
+                    log::info!("Assigned id {} to profile {:?}", new_id, profile_dir);
+                    if let Some(section) = self.profile_sections.remove(&path_id) {
+                        self.profile_sections.insert(new_id.clone(), section);
+                    }
+                    self.path_ids.insert(path_id, new_id.clone());
+                    self.has_path_id_metadata = true;
+                    entry.id = new_id;
+                }
+                _ => {}
+            }
+        }
+    }
 
-            let profile_path = profile_path.unwrap();
-            let profile_is_relative = profile_is_relative.unwrap();
-            let profile_id = calc_profile_id(&profile_path, profile_is_relative);
-            let avatar = avatar_data.avatars.get(&profile_id).map(String::clone);
-            let options = options_data.options
-                .get(&profile_id)
-                .map(HashMap::clone)
-                .unwrap_or_else(HashMap::new);
-
-            state.profile_entries.push(ProfileEntry {
-                id: profile_id,
-                name: profile_name.unwrap(),
-                is_relative: profile_is_relative,
-                path: profile_path,
-                default: profile_default,
-                avatar,
-                options
+    /// Find the current id of a profile, `id` may also be the path based id the profile had
+    /// before it got its own id
+    pub fn canonical_profile_id<'a>(&'a self, id: &'a str) -> Option<&'a str> {
+        if self.profile_entries.iter().any(|p| p.id == id) {
+            Some(id)
+        } else {
+            self.path_ids.get(id).map(String::as_str)
+        }
+    }
+
+    /// Whether the stores still contain metadata keyed by path based ids, it is moved to the
+    /// profile ids the next time the stores are written
+    pub fn has_path_id_metadata(&self) -> bool {
+        self.has_path_id_metadata
+    }
+
+    /// Point a profile at a new folder. The profile keeps its id, section in profiles.ini, avatar
+    /// and options, the id must already be stored in the profile folder.
+    pub fn relocate_profile(&mut self, index: usize, path: String, is_relative: bool) {
+        let profile = &mut self.profile_entries[index];
+        profile.path = path;
+        profile.is_relative = is_relative;
+        profile.revision += 1;
+    }
+
+    /// Clear the default flag of every profile except the specified one, profiles that lose the
+    /// flag get a new revision
+    pub fn clear_other_defaults(&mut self, profile_id: &str) {
+        for profile in self.profile_entries.iter_mut() {
+            if profile.id != profile_id && profile.default {
+                profile.default = false;
+                profile.revision += 1;
+            }
+        }
+    }
+
+    /// Find problems in the profile list
+    pub fn warnings(&self, config: &Config) -> Vec<ProfilesIniWarning> {
+        let mut warnings: Vec<ProfilesIniWarning> = self.quarantined_sections.iter()
+            .map(|q| ProfilesIniWarning::QuarantinedSection {
+                section: q.name.clone(),
+                missing_keys: q.missing_keys.clone()
+            })
+            .collect();
+
+        let defaults: Vec<String> = self.profile_entries.iter()
+            .filter(|p| p.default)
+            .map(|p| p.id.clone())
+            .collect();
+        if defaults.len() > 1 {
+            warnings.push(ProfilesIniWarning::MultipleDefaults { profile_ids: defaults });
+        }
+
+        let mut names: IndexMap<String, Vec<&ProfileEntry>> = IndexMap::new();
+        for profile in &self.profile_entries {
+            names.entry(profile.name.trim().to_ascii_lowercase()).or_default().push(profile);
+        }
+        for duplicates in names.values().filter(|p| p.len() > 1) {
+            warnings.push(ProfilesIniWarning::DuplicateName {
+                name: duplicates[0].name.clone(),
+                profile_ids: duplicates.iter().map(|p| p.id.clone()).collect()
             });
         }
+
+        for profile in &self.profile_entries {
+            let path = profile.full_path(config);
+            if !path.is_dir() {
+                warnings.push(ProfilesIniWarning::MissingDirectory {
+                    profile_id: profile.id.clone(),
+                    path
+                });
+            }
+        }
+
+        warnings
     }
 
-    Ok(state)
+    /// Fix common problems in the profile list, the changes are not written
+    pub fn repair(&mut self, config: &Config) -> Vec<ProfilesIniRepair> {
+        let mut repairs = Vec::new();
+
+        // Restore quarantined sections if we can guess the missing keys
+        let quarantined_sections = std::mem::take(&mut self.quarantined_sections);
+        for quarantined in quarantined_sections {
+            let restored = self.backing_ini.section(Some(quarantined.name.as_str()))
+                .and_then(|prop| {
+                    // The path is the only key we cannot guess
+                    let path = prop.get("Path")?;
+                    let name = prop.get("Name")
+                        .map(str::to_owned)
+                        .or_else(|| Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()))?;
+                    let is_relative = prop.get("IsRelative")
+                        .map(|v| v == "1")
+                        .unwrap_or_else(|| !Path::new(path).is_absolute());
+                    let extra_keys = prop.iter()
+                        .filter(|(k, _)| !["Name", "IsRelative", "Path", "Default"].contains(k))
+                        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                        .collect();
+                    Some(ParsedProfileSection {
+                        name,
+                        is_relative,
+                        path: path.to_owned(),
+                        default: prop.get("Default") == Some("1"),
+                        extra_keys,
+                        key_order: section_key_order(prop)
+                    })
+                });
+            match restored {
+                Some(parsed) => {
+                    // Turn the section into a placeholder so the profile stays in the same position
+                    if let Some(prop) = self.backing_ini.section_mut(Some(quarantined.name.as_str())) {
+                        *prop = Properties::new();
+                    }
+                    let id = self.resolve_id(&config.browser_profile_dir(), &parsed.path, parsed.is_relative);
+                    let (avatar_data, options_data) = (self.retained_avatar_data.clone(), self.retained_options_data.clone());
+                    let entry = self.build_profile_entry(parsed, Some(id), &avatar_data, &options_data);
+                    self.profile_sections.insert(entry.id.clone(), quarantined.name.clone());
+                    repairs.push(ProfilesIniRepair::RestoredSection {
+                        section: quarantined.name,
+                        profile_id: entry.id.clone()
+                    });
+                    self.profile_entries.push(entry);
+                }
+                None => self.quarantined_sections.push(quarantined)
+            }
+        }
+
+        // Only keep the first default profile
+        let mut found_default = false;
+        for profile in self.profile_entries.iter_mut().filter(|p| p.default) {
+            if found_default {
+                profile.default = false;
+                repairs.push(ProfilesIniRepair::UnsetDefault { profile_id: profile.id.clone() });
+            }
+            found_default = true;
+        }
+
+        // Rename duplicate names by adding a number to them
+        for i in 0..self.profile_entries.len() {
+            let is_duplicate = |name: &str, entries: &[ProfileEntry]| entries.iter()
+                .any(|p| p.name.trim().eq_ignore_ascii_case(name.trim()));
+            if !is_duplicate(&self.profile_entries[i].name, &self.profile_entries[..i]) {
+                continue
+            }
+            let base_name = self.profile_entries[i].name.trim().to_owned();
+            let new_name = (2..)
+                .map(|n| format!("{} ({})", base_name, n))
+                .find(|n| !is_duplicate(n, &self.profile_entries))
+                .unwrap();
+            let profile = &mut self.profile_entries[i];
+            profile.name = new_name.clone();
+            profile.revision += 1;
+            repairs.push(ProfilesIniRepair::Renamed { profile_id: profile.id.clone(), new_name });
+        }
+
+        // Firefox will refuse to launch profiles without a folder
+        for profile in &self.profile_entries {
+            let path = profile.full_path(config);
+            if !path.is_dir() {
+                match fs::create_dir_all(&path) {
+                    Ok(_) => repairs.push(ProfilesIniRepair::CreatedDirectory { profile_id: profile.id.clone(), path }),
+                    Err(e) => log::error!("Failed to create missing profile folder {:?}: {:?}", path, e)
+                }
+            }
+        }
+
+        repairs
+    }
 }
 
 #[derive(Debug)]
 pub enum WriteProfilesError {
     WriteIniError(io::Error),
-    OpenAvatarFileError(io::Error),
-    WriteAvatarFileError(serde_json::Error),
-    OpenOptionsFileError(io::Error),
-    WriteOptionsFileError(serde_json::Error),
-    OpenOrderFileError(io::Error),
-    WriteOrderFileError(serde_json::Error),
+    WriteAvatarFileError(io::Error),
+    SerializeAvatarDataError(serde_json::Error),
+    WriteOptionsFileError(io::Error),
+    SerializeOptionsDataError(serde_json::Error),
+    SnapshotError(io::Error),
 }
-pub fn write_profiles(config: &Config, config_dir: &Path, state: &ProfilesIniState) -> Result<(), WriteProfilesError> {
-    // Build avatar data
-    let mut avatar_data = AvatarData {
-        avatars: HashMap::new()
-    };
-    let mut options_data = OptionsData {
-        options: HashMap::new()
-    };
+pub fn write_profiles(app_state: &AppState, state: &ProfilesIniState) -> Result<(), WriteProfilesError> {
+    let config_dir = &app_state.config_dir;
+
+    // Build avatar data, starting with the metadata of profiles we could not parse
+    let mut avatar_data = state.retained_avatar_data.clone();
+    let mut options_data = state.retained_options_data.clone();
     for profile in &state.profile_entries {
         if let Some(avatar) = &profile.avatar {
             avatar_data.avatars.insert(profile.id.clone(), avatar.clone());
         }
         options_data.options.insert(profile.id.clone(), profile.options.clone());
+        options_data.revisions.insert(profile.id.clone(), profile.revision);
     }
 
-    // Write avatar data
-    let avatar_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(avatar_data_path(config_dir))
-        .map_err(WriteProfilesError::OpenAvatarFileError)?;
+    let serialized_avatar_data = serde_json::to_vec(&avatar_data)
+        .map_err(WriteProfilesError::SerializeAvatarDataError)?;
+    let serialized_options_data = serde_json::to_vec(&options_data)
+        .map_err(WriteProfilesError::SerializeOptionsDataError)?;
+
+    // The avatar and options data is written before profiles.ini, put it back if profiles.ini
+    // cannot be written so the stores do not describe profiles that were never written
+    let snapshot = FileSnapshot::take(vec![avatar_data_path(config_dir), options_data_path(config_dir)])
+        .map_err(WriteProfilesError::SnapshotError)?;
+    let result = write_profile_files(app_state, state, &serialized_avatar_data, &serialized_options_data);
+    if result.is_err() {
+        snapshot.restore();
+    }
+    result?;
+
+    bump_state_revision(&app_state.data_dir);
+    Ok(())
+}
+
+fn write_profile_files(app_state: &AppState,
+                       state: &ProfilesIniState,
+                       serialized_avatar_data: &[u8],
+                       serialized_options_data: &[u8]) -> Result<(), WriteProfilesError> {
+    let config = &app_state.config;
+    let config_dir = &app_state.config_dir;
 
-    serde_json::to_writer(avatar_file, &avatar_data)
+    // Write avatar data
+    write_file_atomic(&avatar_data_path(config_dir), serialized_avatar_data)
         .map_err(WriteProfilesError::WriteAvatarFileError)?;
 
     // Write options data
-    let options_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(options_data_path(config_dir))
-        .map_err(WriteProfilesError::OpenOptionsFileError)?;
-
-    serde_json::to_writer(options_file, &options_data)
+    write_file_atomic(&options_data_path(config_dir), serialized_options_data)
         .map_err(WriteProfilesError::WriteOptionsFileError)?;
 
-    // Write profile data
-    let mut new_ini = state.backing_ini.clone();
+    // Keep a copy of the INI files in case we break them
+    backup_profiles_ini(app_state);
 
-    let mut default_profile_path = None::<&str>;
-    for (i, profile) in state.profile_entries.iter().enumerate() {
-        let mut section = &mut new_ini.with_section(Some("Profile".to_owned() + &i.to_string()));
-        section = section.set("Name", profile.name.as_str())
-            .set("IsRelative", if profile.is_relative { "1" } else { "0" })
-            .set("Path", profile.path.as_str());
-        if profile.default {
-            section.set("Default", "1");
-            default_profile_path = Some(&profile.path);
-        }
-    }
+    // Write profile data
+    let mut new_ini = build_profiles_ini(state);
+    let default_profile_path = state.profile_entries.iter()
+        .find(|p| p.default)
+        .map(|p| p.path.as_str());
 
     if let Some(default_profile_path) = default_profile_path {
         for (sec, prop) in &mut new_ini {
@@ -224,9 +670,8 @@ pub fn write_profiles(config: &Config, config_dir: &Path, state: &ProfilesIniSta
         }
     }
 
-    if let Err(e) = new_ini.write_to_file_policy(config.profiles_ini_path(), MOZ_INI_ESCAPE_POLICY) {
-        return Err(WriteProfilesError::WriteIniError(e))
-    }
+    write_ini_atomic(&new_ini, &config.profiles_ini_path())
+        .map_err(WriteProfilesError::WriteIniError)?;
 
     // Write install INI
     if let Some(default_profile_path) = default_profile_path {
@@ -240,7 +685,7 @@ pub fn write_profiles(config: &Config, config_dir: &Path, state: &ProfilesIniSta
                     }
                 }
             }
-            if let Err(e) = installs_conf.write_to_file_policy(config.installs_ini_path(), MOZ_INI_ESCAPE_POLICY) {
+            if let Err(e) = write_ini_atomic(&installs_conf, &config.installs_ini_path()) {
                 log::warn!("Failed to write installs.ini: {:?}", e);
             }
         }
@@ -249,6 +694,78 @@ pub fn write_profiles(config: &Config, config_dir: &Path, state: &ProfilesIniSta
     Ok(())
 }
 
+/// Number of the profile section with the specified name (e.g. 3 for `Profile3`)
+fn profile_section_index(section_name: &str) -> Option<usize> {
+    section_name.strip_prefix("Profile")?.parse().ok()
+}
+
+/// Build the new profiles.ini, this does not sync the default profile to the install sections
+fn build_profiles_ini(state: &ProfilesIniState) -> Ini {
+    let mut new_ini = state.backing_ini.clone();
+
+    // Firefox stops reading profiles at the first missing section number so the sections must be
+    // numbered contiguously. Keep the original section names where possible and give the rest
+    // of the profiles the free numbers.
+    let section_count = state.profile_entries.len() + state.quarantined_sections.len();
+    let mut taken_sections: HashSet<String> = state.quarantined_sections.iter()
+        .map(|q| q.name.clone())
+        .collect();
+    let mut section_names: Vec<Option<String>> = state.profile_entries.iter()
+        .map(|p| state.profile_sections.get(&p.id)
+            .filter(|name| profile_section_index(name).is_some_and(|i| i < section_count))
+            .filter(|name| taken_sections.insert((*name).clone()))
+            .cloned())
+        .collect();
+    let mut free_indexes = (0..).filter(|i| !taken_sections.contains(&format!("Profile{}", i)));
+    for section_name in section_names.iter_mut().filter(|n| n.is_none()) {
+        *section_name = free_indexes.next().map(|i| format!("Profile{}", i));
+    }
+
+    // Remove the placeholders of deleted and renumbered profiles
+    for old_section_name in state.profile_sections.values() {
+        if !section_names.iter().any(|n| n.as_ref() == Some(old_section_name)) {
+            new_ini.delete(Some(old_section_name.as_str()));
+        }
+    }
+
+    for (profile, section_name) in state.profile_entries.iter().zip(section_names) {
+        // Keys are written in their original order, an explicit `Default=0` is kept
+        let has_default_key = profile.key_order.iter().any(|k| k == "Default");
+        let mut keys: Vec<&str> = profile.key_order.iter().map(String::as_str).collect();
+        for key in ["Name", "IsRelative", "Path"].iter().copied().chain(profile.extra_keys.keys().map(String::as_str)) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        if profile.default && !has_default_key {
+            keys.push("Default");
+        }
+
+        let mut section = &mut new_ini.with_section(section_name);
+        for key in keys {
+            let value = match key {
+                "Name" => profile.name.as_str(),
+                "IsRelative" => if profile.is_relative { "1" } else { "0" },
+                "Path" => profile.path.as_str(),
+                "Default" => if profile.default { "1" } else { "0" },
+                _ => match profile.extra_keys.get(key) {
+                    Some(value) => value.as_str(),
+                    None => continue
+                }
+            };
+            section = section.set(key, value);
+        }
+    }
+
+    new_ini
+}
+
+fn write_ini_atomic(ini: &Ini, path: &Path) -> io::Result<()> {
+    let mut serialized = Vec::new();
+    ini.write_to_policy(&mut serialized, MOZ_INI_ESCAPE_POLICY)?;
+    write_file_atomic(path, &serialized)
+}
+
 pub fn calc_profile_id(path: &str, is_relative: bool) -> String {
     let mut context = Context::new(&SHA256);
     context.update(&[is_relative as u8]);
@@ -256,10 +773,160 @@ pub fn calc_profile_id(path: &str, is_relative: bool) -> String {
     return HEXUPPER.encode(context.finish().as_ref());
 }
 
-pub fn check_profile_active(profile_path: &Path) -> bool {
-    [
-        profile_path.join("cookies.sqlite-wal"),
-        profile_path.join("webappsstore.sqlite-wal"),
-        profile_path.join("places.sqlite-wal")
-    ].iter().any(|file| file.exists())
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use ini::Ini;
+    use crate::test_util::{app_state_for, test_app_state, TempDir};
+    use super::*;
+
+    fn corpus_files(prefix: &str) -> Vec<PathBuf> {
+        let corpus_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("corpus");
+        let mut files: Vec<PathBuf> = fs::read_dir(corpus_dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with(prefix))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty(), "No {}* files in corpus", prefix);
+        files
+    }
+
+    fn load_corpus_file(path: &Path) -> Ini {
+        Ini::load_from_file_opt(path, MOZ_INI_PARSE_OPTION)
+            .unwrap_or_else(|e| panic!("Failed to parse {}: {:?}", path.display(), e))
+    }
+
+    // Write the INI file the same way we write it to disk and read it back
+    fn rewrite(ini: &Ini) -> Ini {
+        let mut serialized = Vec::new();
+        ini.write_to_policy(&mut serialized, MOZ_INI_ESCAPE_POLICY).unwrap();
+        Ini::load_from_str_opt(&String::from_utf8(serialized).unwrap(), MOZ_INI_PARSE_OPTION).unwrap()
+    }
+
+    type IniContents = Vec<(Option<String>, Vec<(String, String)>)>;
+
+    // Every section with its keys in order, ignoring an empty general section
+    fn ini_contents(ini: &Ini) -> IniContents {
+        ini.iter()
+            .filter(|(sec, prop)| sec.is_some() || !prop.is_empty())
+            .map(|(sec, prop)| (
+                sec.map(str::to_owned),
+                prop.iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+            ))
+            .collect()
+    }
+
+    #[test]
+    fn profiles_ini_round_trip() {
+        for path in corpus_files("profiles-") {
+            let original = load_corpus_file(&path);
+            let state = parse_profiles_ini(&original, &AvatarData::default(), &OptionsData::default(), None);
+            let rewritten = rewrite(&build_profiles_ini(&state));
+            assert_eq!(ini_contents(&rewritten), ini_contents(&original), "{} did not round-trip", path.display());
+        }
+    }
+
+    #[test]
+    fn write_profiles_keeps_unknown_installs_ini_contents() {
+        for path in corpus_files("installs-") {
+            let root = TempDir::new("profiles-test");
+            let state = test_app_state(&root);
+            // Add a key and a section that we do not know about
+            let original = fs::read_to_string(&path).unwrap()
+                .replacen("Default=", "Custom=1\nDefault=", 1)
+                + "\n[Unknown]\nKey=Value\n";
+            fs::write(state.config.installs_ini_path(), &original).unwrap();
+
+            let profiles = read_profiles(&state.config, &state.config_dir).unwrap();
+            write_profiles(&state, &profiles).unwrap();
+
+            let original = Ini::load_from_str_opt(&original, MOZ_INI_PARSE_OPTION).unwrap();
+            let written = Ini::load_from_file_opt(state.config.installs_ini_path(), MOZ_INI_PARSE_OPTION).unwrap();
+            let without_default = |ini: &Ini| -> IniContents {
+                ini_contents(ini).into_iter()
+                    .map(|(sec, prop)| (sec, prop.into_iter().filter(|(k, _)| k != "Default" && k != "Locked").collect()))
+                    .collect()
+            };
+            assert_eq!(without_default(&written), without_default(&original), "{} lost contents", path.display());
+            assert_eq!(written.get_from(Some("Unknown"), "Key"), Some("Value"), "{} lost the unknown section", path.display());
+            for (sec, prop) in written.iter().filter(|(sec, _)| sec.is_some() && *sec != Some("Unknown")) {
+                assert_eq!(prop.get("Default"), Some("Profiles/a.default"), "{} did not update {:?}", path.display(), sec);
+                assert_eq!(prop.get("Locked"), Some("0"), "{} did not unlock {:?}", path.display(), sec);
+            }
+        }
+    }
+
+    #[test]
+    fn removing_profile_keeps_sections_contiguous() {
+        for path in corpus_files("profiles-") {
+            let mut state = parse_profiles_ini(&load_corpus_file(&path), &AvatarData::default(), &OptionsData::default(), None);
+            let removed = state.profile_entries.remove(0);
+            let rewritten = build_profiles_ini(&state);
+
+            let section_count = state.profile_entries.len() + state.quarantined_sections.len();
+            for i in 0..section_count {
+                assert!(rewritten.section(Some(format!("Profile{}", i))).is_some(), "{} is missing Profile{}", path.display(), i);
+            }
+            assert!(rewritten.section(Some(format!("Profile{}", section_count))).is_none(), "{} has too many sections", path.display());
+            assert!(!rewritten.iter().any(|(_, prop)| prop.get("Path") == Some(removed.path.as_str())), "{} still contains removed profile", path.display());
+        }
+    }
+
+    #[test]
+    fn failed_ini_write_restores_avatar_and_options_data() {
+        let root = TempDir::new("profiles-test");
+        let state = test_app_state(&root);
+        let mut profiles = read_profiles(&state.config, &state.config_dir).unwrap();
+        write_profiles(&state, &profiles).unwrap();
+
+        let stores = [avatar_data_path(&state.config_dir), options_data_path(&state.config_dir)];
+        let before: Vec<Vec<u8>> = stores.iter().map(|p| fs::read(p).unwrap()).collect();
+
+        // profiles.ini cannot be written as its folder does not exist
+        profiles.profile_entries[0].avatar = Some("custom:avatar".to_owned());
+        profiles.profile_entries[0].options.insert("theme".to_owned(), "dark".into());
+        let broken_state = app_state_for(&root, &root.join("missing"));
+        assert!(matches!(write_profiles(&broken_state, &profiles), Err(WriteProfilesError::WriteIniError(_))));
+
+        let after: Vec<Vec<u8>> = stores.iter().map(|p| fs::read(p).unwrap()).collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn profile_ids_are_only_assigned_explicitly() {
+        let root = TempDir::new("profiles-test");
+        let state = test_app_state(&root);
+        let profile_dir = state.config.browser_profile_dir().join("Profiles").join("a.default");
+        fs::create_dir_all(&profile_dir).unwrap();
+        let path_id = calc_profile_id("Profiles/a.default", true);
+
+        // Reading the profile list does not touch the profile folders
+        let mut profiles = read_profiles(&state.config, &state.config_dir).unwrap();
+        assert_eq!(profiles.profile_entries[0].id, path_id);
+        assert_eq!(check_profile_id_marker(&profile_dir), ProfileIdMarker::Missing);
+
+        profiles.repair_profile_ids(&state.config);
+        let id = profiles.profile_entries[0].id.clone();
+        assert_ne!(id, path_id);
+        assert_eq!(read_profile_id_marker(&profile_dir), Some(id.clone()));
+        assert_eq!(profiles.canonical_profile_id(&path_id), Some(id.as_str()));
+
+        // New profiles get an id when they are registered
+        let mut new_profile = ProfileEntry::new_relative("New", None, HashMap::new());
+        let new_profile_dir = new_profile.full_path(&state.config);
+        fs::create_dir_all(&new_profile_dir).unwrap();
+        profiles.assign_profile_id(&mut new_profile, &state.config);
+        assert_ne!(new_profile.id, calc_profile_id(&new_profile.path, true));
+        assert_eq!(read_profile_id_marker(&new_profile_dir), Some(new_profile.id.clone()));
+
+        // Profiles that already have an id keep it
+        let mut restored_profile = ProfileEntry::new_relative("Restored", None, HashMap::new());
+        let restored_profile_dir = restored_profile.full_path(&state.config);
+        fs::rename(&new_profile_dir, &restored_profile_dir).unwrap();
+        profiles.assign_profile_id(&mut restored_profile, &state.config);
+        assert_eq!(restored_profile.id, new_profile.id);
+        assert_eq!(check_profile_id_marker(&restored_profile_dir), ProfileIdMarker::Owned(new_profile.id.clone()));
+    }
 }
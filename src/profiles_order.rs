@@ -7,11 +7,14 @@ use crate::ipc::notify_update_profile_order;
 use crate::native_resp::{NativeResponseEvent, write_native_event};
 use crate::profiles::ProfilesIniState;
 use crate::state::{AppContext, AppState};
-use crate::storage::order_data_path;
+use crate::storage::{bump_state_revision, order_data_path, write_file_atomic};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct OrderData {
-    pub order: Vec<String>
+    pub order: Vec<String>,
+    /// Incremented every time the order changes, used to detect conflicting edits
+    #[serde(default)]
+    pub revision: u64
 }
 
 impl OrderData {
@@ -20,7 +23,10 @@ impl OrderData {
     pub fn recalculate(&mut self, profiles: &ProfilesIniState) {
         let mut profile_indicies: HashMap<&str, usize> = HashMap::new();
         for (idx, profile_id) in self.order.iter().enumerate() {
-            profile_indicies.insert(profile_id, idx);
+            // The order may still refer to profiles by their path based id
+            if let Some(profile_id) = profiles.canonical_profile_id(profile_id) {
+                profile_indicies.entry(profile_id).or_insert(idx);
+            }
         }
         let profile_idx = |id: &str| profile_indicies.get(id)
             .copied()
@@ -40,9 +46,22 @@ impl OrderData {
     /// - ipc notify profile order updated
     /// Will log if the write fails.
     pub fn try_rewrite(context: &AppContext, profiles: &ProfilesIniState) {
+        Self::try_rewrite_placing(context, profiles, None)
+    }
+
+    /// Same as `try_rewrite` but also moves a profile to the specified position in the order
+    pub fn try_rewrite_placing(context: &AppContext, profiles: &ProfilesIniState, placement: Option<(&str, usize)>) {
         let mut order_data = Self::read(&context.state.config_dir);
         order_data.recalculate(profiles);
-        if let Err(e) = order_data.write(&context.state.config_dir) {
+        if let Some((profile_id, position)) = placement {
+            if let Some(cur_position) = order_data.order.iter().position(|id| id == profile_id) {
+                let id = order_data.order.remove(cur_position);
+                let position = position.min(order_data.order.len());
+                order_data.order.insert(position, id);
+            }
+        }
+        order_data.revision += 1;
+        if let Err(e) = order_data.write(context.state) {
             log::error!("Failed to update profiles order: {:?}", e);
         } else {
             notify_update_profile_order(context, profiles);
@@ -62,22 +81,22 @@ impl OrderData {
             })
     }
 
-    pub fn write(&self, config_dir: &Path) -> eyre::Result<()> {
+    pub fn write(&self, app_state: &AppState) -> eyre::Result<()> {
         // Write order data
-        let order_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(order_data_path(config_dir))
-            .context("failed to open profile order data file for writing")?;
+        let serialized = serde_json::to_vec(&self)
+            .context("failed to serialize profile order data")?;
 
-        serde_json::to_writer(order_file, &self)
-            .context("failed to write profile order data to file")
+        write_file_atomic(&order_data_path(&app_state.config_dir), &serialized)
+            .context("failed to write profile order data to file")?;
+        bump_state_revision(&app_state.data_dir);
+        Ok(())
     }
 }
 
 pub fn native_notify_updated_profile_order(app_state: &AppState) {
+    let order_data = OrderData::read(&app_state.config_dir);
     write_native_event(NativeResponseEvent::ProfileOrderUpdated {
-        order: OrderData::read(&app_state.config_dir).order
+        order: order_data.order,
+        revision: order_data.revision
     });
 }
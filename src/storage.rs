@@ -1,5 +1,8 @@
+use std::fs;
+use std::io;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use crate::{AppContext};
+use ulid::Ulid;
 
 pub fn global_options_data_path(config_dir: &Path) -> PathBuf {
     config_dir.join("global-options.json")
@@ -17,6 +20,157 @@ pub fn order_data_path(config_dir: &Path) -> PathBuf {
     config_dir.join("profile-order.json")
 }
 
-pub fn custom_avatars_path(context: &AppContext) -> PathBuf {
-    context.state.data_dir.join("avatars")
-}
\ No newline at end of file
+pub fn prefs_data_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("profile-prefs.json")
+}
+
+pub fn custom_avatars_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("avatars")
+}
+
+pub fn backups_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("backups")
+}
+
+pub fn trash_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("trash")
+}
+
+pub fn templates_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("templates")
+}
+
+pub fn state_revision_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("state-revision")
+}
+
+/// Replace the contents of a file without ever leaving it empty or half-written: the new contents
+/// are written and synced to a temporary file which is then renamed over the original file.
+pub fn write_file_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(format!(".{}.tmp", Ulid::new()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(&tmp_path)
+        .and_then(|mut f| {
+            f.write_all(contents)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // Make sure the rename itself survives a crash
+    #[cfg(target_family = "unix")]
+    if let Some(parent) = path.parent() {
+        if let Err(e) = fs::File::open(parent).and_then(|d| d.sync_all()) {
+            log::warn!("Failed to sync directory {:?}: {:?}", parent, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// The contents of some files at one point in time, used to undo a group of writes when one of
+/// them fails. Files that did not exist are deleted on restore.
+pub struct FileSnapshot {
+    files: Vec<(PathBuf, Option<Vec<u8>>)>
+}
+
+impl FileSnapshot {
+    pub fn take(paths: Vec<PathBuf>) -> io::Result<FileSnapshot> {
+        let files = paths.into_iter()
+            .map(|path| match fs::read(&path) {
+                Ok(contents) => Ok((path, Some(contents))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((path, None)),
+                Err(e) => Err(e)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(FileSnapshot { files })
+    }
+
+    /// Put every file back the way it was, will log if a file cannot be restored
+    pub fn restore(&self) {
+        for (path, contents) in &self.files {
+            let result = match contents {
+                Some(contents) => write_file_atomic(path, contents),
+                None => match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(())
+                }
+            };
+            if let Err(e) = result {
+                log::error!("Failed to restore {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+/// Revision of the profile list, options, avatars and order. It is a counter that is bumped
+/// every time one of them is written, changes made outside the connector (e.g. by the browser's
+/// own profile manager) are not counted.
+pub fn read_state_revision(data_dir: &Path) -> u64 {
+    try_read_state_revision(data_dir).unwrap_or(0)
+}
+
+fn try_read_state_revision(data_dir: &Path) -> Option<u64> {
+    fs::read_to_string(state_revision_path(data_dir)).ok()
+        .and_then(|r| r.trim().parse().ok())
+}
+
+/// Increase the state revision after a store was written, the store lock must be held. Will log
+/// if the revision cannot be written.
+pub fn bump_state_revision(data_dir: &Path) {
+    let revision = read_state_revision(data_dir) + 1;
+    if let Err(e) = write_file_atomic(&state_revision_path(data_dir), revision.to_string().as_bytes()) {
+        log::error!("Failed to bump state revision: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use super::*;
+
+    // Names of all files in the folder
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn write_file_atomic_replaces_file() {
+        let dir = TempDir::new("storage-test");
+        let path = dir.join("store.json");
+
+        write_file_atomic(&path, b"first").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        write_file_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(file_names(&dir), vec!["store.json"]);
+    }
+
+    #[test]
+    fn failed_write_file_atomic_removes_temp_file() {
+        let dir = TempDir::new("storage-test");
+        // A file cannot replace a non-empty folder so the rename fails
+        let path = dir.join("store.json");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("contents"), b"contents").unwrap();
+
+        assert!(write_file_atomic(&path, b"contents").is_err());
+        assert!(path.is_dir());
+        assert_eq!(file_names(&dir), vec!["store.json"]);
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+use crate::extensions::{addon_id, addon_name, addon_xpi_path, install_addons, read_extensions_json, select_addons, AddonChunk, ExtensionsError};
+use crate::storage::{templates_path, write_file_atomic};
+
+// === TEMPLATES ===
+
+// Templates are used to pre-seed new profiles. Every template has a folder in the data dir containing:
+// - template.json: the name of the template and the extensions.json chunks of its addons
+// - files/: the profile files that are copied into new profiles
+// - extensions/: the XPIs of the addons
+const MANIFEST_FILENAME: &str = "template.json";
+const FILES_DIRNAME: &str = "files";
+const EXTENSIONS_DIRNAME: &str = "extensions";
+
+/// Profile files that are saved in templates if the profile has them
+pub const TEMPLATE_FILES: &[&str] = &["user.js", "handlers.json", "search.json.mozlz4", "containers.json"];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TemplateManifest {
+    id: String,
+    name: String,
+    /// Unix timestamp in milliseconds
+    created_at: i64,
+    files: Vec<String>,
+    /// Of the extensions.json the addons were taken from
+    schema_version: Option<Value>,
+    addons: Vec<AddonChunk>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TemplateInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub files: Vec<String>,
+    pub extensions: Vec<TemplateExtension>
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TemplateExtension {
+    pub id: String,
+    pub name: Option<String>
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    TemplateNotFound,
+    /// The addon is not installed in the profile or has no XPI (e.g. builtin addons)
+    ExtensionNotFound(String),
+    ReadManifestError(io::Error),
+    BadManifest(serde_json::Error),
+    WriteManifestError(io::Error),
+    SerializeManifestError(serde_json::Error),
+    CopyFileError(io::Error),
+    ExtensionsError(ExtensionsError),
+    DeleteError(io::Error)
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::TemplateNotFound => write!(f, "no such template"),
+            TemplateError::ExtensionNotFound(id) => write!(f, "extension {} has no installable copy in the profile", id),
+            TemplateError::ReadManifestError(e) => write!(f, "failed to read the template manifest: {}", e),
+            TemplateError::BadManifest(e) => write!(f, "the template manifest is invalid: {}", e),
+            TemplateError::WriteManifestError(e) => write!(f, "failed to write the template: {}", e),
+            TemplateError::SerializeManifestError(e) => write!(f, "failed to serialize the template manifest: {}", e),
+            TemplateError::CopyFileError(e) => write!(f, "failed to copy a template file: {}", e),
+            TemplateError::ExtensionsError(e) => write!(f, "{}", e),
+            TemplateError::DeleteError(e) => write!(f, "failed to delete the template: {}", e)
+        }
+    }
+}
+
+impl From<&TemplateManifest> for TemplateInfo {
+    fn from(manifest: &TemplateManifest) -> Self {
+        TemplateInfo {
+            id: manifest.id.clone(),
+            name: manifest.name.clone(),
+            created_at: manifest.created_at,
+            files: manifest.files.clone(),
+            extensions: manifest.addons.iter()
+                .filter_map(|addon| Some(TemplateExtension {
+                    id: addon_id(addon)?.to_owned(),
+                    name: addon_name(addon).map(str::to_owned)
+                }))
+                .collect()
+        }
+    }
+}
+
+/// List all templates sorted by name
+pub fn list_templates(data_dir: &Path) -> Vec<TemplateInfo> {
+    let mut templates: Vec<TemplateInfo> = match fs::read_dir(templates_path(data_dir)) {
+        Ok(r) => r.filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter_map(|id| read_manifest(data_dir, &id)
+                .map_err(|e| log::warn!("Skipping unreadable template {}: {:?}", id, e))
+                .ok())
+            .map(|m| TemplateInfo::from(&m))
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    templates.sort_by_cached_key(|t| t.name.to_lowercase());
+    templates
+}
+
+/// Save the template files and the specified addons of a profile as a new template
+pub fn save_template(data_dir: &Path, name: &str, profile_dir: &Path, extension_ids: &[String]) -> Result<TemplateInfo, TemplateError> {
+    let id = Ulid::new().to_string();
+    let template_dir = templates_path(data_dir).join(&id);
+    let result = write_template(&template_dir, &id, name, profile_dir, extension_ids);
+    if result.is_err() {
+        if let Err(e) = fs::remove_dir_all(&template_dir) {
+            log::error!("Failed to clean up partially saved template: {:?}", e);
+        }
+    }
+    result
+}
+
+fn write_template(template_dir: &Path, id: &str, name: &str, profile_dir: &Path, extension_ids: &[String]) -> Result<TemplateInfo, TemplateError> {
+    fs::create_dir_all(template_dir.join(FILES_DIRNAME)).map_err(TemplateError::WriteManifestError)?;
+
+    let mut files = Vec::new();
+    for file in TEMPLATE_FILES.iter().filter(|f| profile_dir.join(f).is_file()) {
+        fs::copy(profile_dir.join(file), template_dir.join(FILES_DIRNAME).join(file))
+            .map_err(TemplateError::CopyFileError)?;
+        files.push(file.to_string());
+    }
+
+    let mut schema_version = None;
+    let mut addons = Vec::new();
+    if !extension_ids.is_empty() {
+        let extensions_json = read_extensions_json(profile_dir).map_err(TemplateError::ExtensionsError)?;
+        schema_version = extensions_json.get("schemaVersion").cloned();
+        let selected = select_addons(&extensions_json, extension_ids).map_err(|e| match e {
+            ExtensionsError::ExtensionNotFound(id) => TemplateError::ExtensionNotFound(id),
+            e => TemplateError::ExtensionsError(e)
+        })?;
+        fs::create_dir_all(template_dir.join(EXTENSIONS_DIRNAME)).map_err(TemplateError::WriteManifestError)?;
+        for (chunk, xpi_path) in selected {
+            let xpi_filename = xpi_path.file_name()
+                .ok_or_else(|| TemplateError::ExtensionNotFound(addon_id(&chunk).unwrap_or_default().to_owned()))?;
+            fs::copy(&xpi_path, template_dir.join(EXTENSIONS_DIRNAME).join(xpi_filename))
+                .map_err(TemplateError::CopyFileError)?;
+            addons.push(chunk);
+        }
+    }
+
+    let manifest = TemplateManifest {
+        id: id.to_owned(),
+        name: name.trim().to_owned(),
+        created_at: chrono::Utc::now().timestamp_millis(),
+        files,
+        schema_version,
+        addons
+    };
+    let serialized = serde_json::to_vec(&manifest).map_err(TemplateError::SerializeManifestError)?;
+    write_file_atomic(&template_dir.join(MANIFEST_FILENAME), &serialized)
+        .map_err(TemplateError::WriteManifestError)?;
+
+    log::trace!("Saved profile {:?} as template {}", profile_dir, id);
+    Ok(TemplateInfo::from(&manifest))
+}
+
+/// Copy the files and addons of a template into a profile folder, existing files are replaced
+pub fn apply_template(data_dir: &Path, id: &str, profile_dir: &Path) -> Result<(), TemplateError> {
+    let manifest = read_manifest(data_dir, id)?;
+    let template_dir = template_path(data_dir, id)?;
+
+    for file in &manifest.files {
+        // Do not trust the manifest with paths
+        if !TEMPLATE_FILES.contains(&file.as_str()) {
+            continue
+        }
+        fs::copy(template_dir.join(FILES_DIRNAME).join(file), profile_dir.join(file))
+            .map_err(TemplateError::CopyFileError)?;
+    }
+
+    let addons: Vec<(AddonChunk, PathBuf)> = manifest.addons.into_iter()
+        .filter_map(|chunk| {
+            let xpi_filename = addon_xpi_path(&chunk)?.file_name()?.to_owned();
+            Some((chunk, template_dir.join(EXTENSIONS_DIRNAME).join(xpi_filename)))
+        })
+        .collect();
+    if !addons.is_empty() {
+        install_addons(profile_dir, manifest.schema_version.as_ref(), addons)
+            .map_err(TemplateError::ExtensionsError)?;
+    }
+
+    log::trace!("Applied template {} to profile {:?}", id, profile_dir);
+    Ok(())
+}
+
+pub fn delete_template(data_dir: &Path, id: &str) -> Result<(), TemplateError> {
+    fs::remove_dir_all(template_path(data_dir, id)?)
+        .map_err(TemplateError::DeleteError)
+}
+
+fn template_path(data_dir: &Path, id: &str) -> Result<PathBuf, TemplateError> {
+    // Template IDs are ULIDs, do not accept anything that could point outside the templates folder
+    if id.parse::<Ulid>().is_err() {
+        return Err(TemplateError::TemplateNotFound);
+    }
+    let template_dir = templates_path(data_dir).join(id);
+    if !template_dir.is_dir() {
+        return Err(TemplateError::TemplateNotFound);
+    }
+    Ok(template_dir)
+}
+
+fn read_manifest(data_dir: &Path, id: &str) -> Result<TemplateManifest, TemplateError> {
+    let file = match OpenOptions::new()
+        .read(true)
+        .open(template_path(data_dir, id)?.join(MANIFEST_FILENAME)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(TemplateError::TemplateNotFound),
+        Err(e) => return Err(TemplateError::ReadManifestError(e))
+    };
+    serde_json::from_reader(file).map_err(TemplateError::BadManifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::extensions::{find_addon_chunk, EXTENSIONS_JSON_FILENAME};
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn template_round_trip() {
+        let root = TempDir::new("template-test");
+        let data_dir = root.join("data");
+        let profile_dir = root.join("profile");
+        fs::create_dir_all(profile_dir.join("extensions")).unwrap();
+        fs::write(profile_dir.join("user.js"), "user_pref(\"a\", 1);").unwrap();
+        fs::write(profile_dir.join("prefs.js"), "user_pref(\"b\", 1);").unwrap();
+        let xpi_path = profile_dir.join("extensions").join("addon@example.com.xpi");
+        fs::write(&xpi_path, "xpi").unwrap();
+        let extensions_json = json!({
+            "schemaVersion": 35,
+            "addons": [{ "id": "addon@example.com", "path": xpi_path, "defaultLocale": { "name": "Addon" } }]
+        });
+        fs::write(profile_dir.join(EXTENSIONS_JSON_FILENAME), extensions_json.to_string()).unwrap();
+
+        let template = save_template(&data_dir, "Work", &profile_dir, &["addon@example.com".to_owned()]).unwrap();
+        assert_eq!(template.files, vec!["user.js".to_owned()]);
+        assert_eq!(template.extensions[0].name.as_deref(), Some("Addon"));
+        assert!(matches!(
+            save_template(&data_dir, "Broken", &profile_dir, &["missing@example.com".to_owned()]),
+            Err(TemplateError::ExtensionNotFound(_))
+        ));
+        assert_eq!(list_templates(&data_dir).len(), 1);
+
+        let new_profile_dir = root.join("new-profile");
+        fs::create_dir_all(&new_profile_dir).unwrap();
+        apply_template(&data_dir, &template.id, &new_profile_dir).unwrap();
+        assert!(new_profile_dir.join("user.js").is_file());
+        assert!(!new_profile_dir.join("prefs.js").exists());
+        let new_xpi_path = new_profile_dir.join("extensions").join("addon@example.com.xpi");
+        assert!(new_xpi_path.is_file());
+        let new_extensions_json = read_extensions_json(&new_profile_dir).unwrap();
+        let chunk = find_addon_chunk(&new_extensions_json, "addon@example.com").unwrap();
+        assert_eq!(addon_xpi_path(chunk), Some(new_xpi_path));
+
+        delete_template(&data_dir, &template.id).unwrap();
+        assert!(list_templates(&data_dir).is_empty());
+    }
+}
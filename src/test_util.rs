@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use serde_json::json;
+use ulid::Ulid;
+use crate::state::AppState;
+
+// === TEST UTILITIES ===
+
+/// A new folder in the system temp dir that is deleted again when dropped, even if the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("{}-{}", prefix, Ulid::new()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            eprintln!("Failed to remove temp dir {:?}: {:?}", self.0, e);
+        }
+    }
+}
+
+/// App state with its config, data and browser profile folders inside `root`, the browser profile
+/// folder gets a profiles.ini with a single relative profile
+pub fn test_app_state(root: &Path) -> AppState {
+    let browser_profile_dir = root.join("browser");
+    let state = app_state_for(root, &browser_profile_dir);
+    fs::create_dir_all(&browser_profile_dir).unwrap();
+    fs::create_dir_all(&state.config_dir).unwrap();
+    fs::create_dir_all(&state.data_dir).unwrap();
+    fs::write(state.config.profiles_ini_path(), concat!(
+        "[General]\nStartWithLastProfile=1\nVersion=2\n\n",
+        "[Profile0]\nName=default\nIsRelative=1\nPath=Profiles/a.default\nDefault=1\n"
+    )).unwrap();
+    state
+}
+
+/// App state with its config and data folders inside `root` that uses the specified browser
+/// profile folder, nothing is created
+pub fn app_state_for(root: &Path, browser_profile_dir: &Path) -> AppState {
+    AppState {
+        config: serde_json::from_value(json!({ "browser_profile_dir": browser_profile_dir })).unwrap(),
+        first_run: false,
+        cur_profile_id: None,
+        extension_id: None,
+        extension_version: None,
+        internal_extension_id: None,
+        config_dir: root.join("config"),
+        data_dir: root.join("data"),
+    }
+}
@@ -0,0 +1,259 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+use crate::profile_files::move_dir;
+use crate::profiles::ProfileEntry;
+use crate::state::AppState;
+use crate::storage::{trash_path, write_file_atomic};
+
+// === TRASH ===
+
+// Every deleted profile gets a folder in the trash containing:
+// - entry.json: the metadata of the profile
+// - profile/: the profile folder (missing if the profile folder did not exist)
+const ENTRY_FILENAME: &str = "entry.json";
+const PROFILE_DIRNAME: &str = "profile";
+
+const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashEntry {
+    pub id: String,
+    /// Unix timestamp in milliseconds
+    pub deleted_at: i64,
+    pub name: String,
+    pub is_relative: bool,
+    /// Path of the profile folder before it was deleted
+    pub path: String,
+    pub avatar: Option<String>,
+    pub options: HashMap<String, Value>,
+    /// Position of the profile in the profile order before it was deleted
+    pub order_position: Option<usize>
+}
+
+#[derive(Debug)]
+pub enum TrashError {
+    EntryNotFound,
+    ReadEntryError(io::Error),
+    BadEntryError(serde_json::Error),
+    WriteEntryError(io::Error),
+    SerializeEntryError(serde_json::Error),
+    MoveProfileError(io::Error),
+    DeleteEntryError(io::Error)
+}
+
+impl fmt::Display for TrashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrashError::EntryNotFound => write!(f, "no such trash entry"),
+            TrashError::ReadEntryError(e) => write!(f, "failed to read the trash entry: {}", e),
+            TrashError::BadEntryError(e) => write!(f, "the trash entry is invalid: {}", e),
+            TrashError::WriteEntryError(e) => write!(f, "failed to write the trash entry: {}", e),
+            TrashError::SerializeEntryError(e) => write!(f, "failed to serialize the trash entry: {}", e),
+            TrashError::MoveProfileError(e) => write!(f, "failed to move the profile folder: {}", e),
+            TrashError::DeleteEntryError(e) => write!(f, "failed to delete the trash entry: {}", e)
+        }
+    }
+}
+
+/// Move a profile folder into the trash together with the profile's metadata. The trash is left
+/// untouched if this fails.
+pub fn move_to_trash(data_dir: &Path,
+                     profile: &ProfileEntry,
+                     profile_path: &Path,
+                     order_position: Option<usize>) -> Result<TrashEntry, TrashError> {
+    let entry = TrashEntry {
+        id: Ulid::new().to_string(),
+        deleted_at: chrono::Utc::now().timestamp_millis(),
+        name: profile.name.clone(),
+        is_relative: profile.is_relative,
+        path: profile.path.clone(),
+        avatar: profile.avatar.clone(),
+        options: profile.options.clone(),
+        order_position
+    };
+
+    put_in_trash(data_dir, &entry, profile_path)?;
+    log::trace!("Moved profile {:?} to trash: {}", profile_path, entry.id);
+    Ok(entry)
+}
+
+/// Put a profile folder that was taken from the trash back into the trash under its old entry,
+/// e.g. when the restored profile could not be saved
+pub fn return_to_trash(data_dir: &Path, entry: &TrashEntry, profile_path: &Path) -> Result<(), TrashError> {
+    put_in_trash(data_dir, entry, profile_path)?;
+    log::trace!("Returned profile {:?} to trash: {}", profile_path, entry.id);
+    Ok(())
+}
+
+fn put_in_trash(data_dir: &Path, entry: &TrashEntry, profile_path: &Path) -> Result<(), TrashError> {
+    let entry_dir = trash_path(data_dir).join(&entry.id);
+    let result = write_entry(&entry_dir, entry)
+        .and_then(|_| match move_dir(profile_path, &entry_dir.join(PROFILE_DIRNAME)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(TrashError::MoveProfileError(e)),
+            _ => Ok(())
+        });
+    if result.is_err() {
+        if let Err(e) = fs::remove_dir_all(&entry_dir) {
+            log::error!("Failed to clean up trash entry: {:?}", e);
+        }
+    }
+    result
+}
+
+fn write_entry(entry_dir: &Path, entry: &TrashEntry) -> Result<(), TrashError> {
+    fs::create_dir_all(entry_dir).map_err(TrashError::WriteEntryError)?;
+    let serialized = serde_json::to_vec(entry).map_err(TrashError::SerializeEntryError)?;
+    write_file_atomic(&entry_dir.join(ENTRY_FILENAME), &serialized)
+        .map_err(TrashError::WriteEntryError)
+}
+
+/// List all profiles in the trash, most recently deleted first
+pub fn list_trash(data_dir: &Path) -> Vec<TrashEntry> {
+    let mut entries: Vec<TrashEntry> = match fs::read_dir(trash_path(data_dir)) {
+        Ok(r) => r.filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter_map(|id| read_trash_entry(data_dir, &id)
+                .map_err(|e| log::warn!("Skipping unreadable trash entry {}: {:?}", id, e))
+                .ok())
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    entries.sort_unstable_by_key(|e| Reverse(e.deleted_at));
+    entries
+}
+
+fn trash_entry_path(data_dir: &Path, id: &str) -> Result<PathBuf, TrashError> {
+    // Trash IDs are ULIDs, do not accept anything that could point outside the trash
+    if id.parse::<Ulid>().is_err() {
+        return Err(TrashError::EntryNotFound);
+    }
+    let entry_dir = trash_path(data_dir).join(id);
+    if !entry_dir.is_dir() {
+        return Err(TrashError::EntryNotFound);
+    }
+    Ok(entry_dir)
+}
+
+pub fn read_trash_entry(data_dir: &Path, id: &str) -> Result<TrashEntry, TrashError> {
+    let file = match OpenOptions::new()
+        .read(true)
+        .open(trash_entry_path(data_dir, id)?.join(ENTRY_FILENAME)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(TrashError::EntryNotFound),
+        Err(e) => return Err(TrashError::ReadEntryError(e))
+    };
+    serde_json::from_reader(file).map_err(TrashError::BadEntryError)
+}
+
+/// Move the profile folder of a trash entry to `target` and remove the entry from the trash.
+/// An empty folder is created if the profile had no folder when it was deleted.
+pub fn take_from_trash(data_dir: &Path, id: &str, target: &Path) -> Result<TrashEntry, TrashError> {
+    let entry = read_trash_entry(data_dir, id)?;
+    let entry_dir = trash_entry_path(data_dir, id)?;
+    let trashed_profile_dir = entry_dir.join(PROFILE_DIRNAME);
+
+    if trashed_profile_dir.exists() {
+        move_dir(&trashed_profile_dir, target)
+    } else {
+        fs::create_dir_all(target)
+    }.map_err(TrashError::MoveProfileError)?;
+
+    if let Err(e) = fs::remove_dir_all(&entry_dir) {
+        log::warn!("Failed to remove restored trash entry {}: {:?}", id, e);
+    }
+
+    Ok(entry)
+}
+
+/// Permanently delete a trash entry and its profile folder
+pub fn purge_trash_entry(data_dir: &Path, id: &str) -> Result<(), TrashError> {
+    fs::remove_dir_all(trash_entry_path(data_dir, id)?)
+        .map_err(TrashError::DeleteEntryError)
+}
+
+/// Permanently delete trash entries older than the configured retention period. Will log if
+/// an entry cannot be deleted.
+pub fn purge_expired_trash(app_state: &AppState) {
+    let retention_days = app_state.config.trash_retention_days();
+    if retention_days == 0 {
+        return
+    }
+    let cutoff = chrono::Utc::now().timestamp_millis() - retention_days as i64 * MS_PER_DAY;
+
+    for entry in list_trash(&app_state.data_dir).iter().filter(|e| e.deleted_at < cutoff) {
+        log::trace!("Purging expired trash entry: {}", entry.id);
+        if let Err(e) = purge_trash_entry(&app_state.data_dir, &entry.id) {
+            log::error!("Failed to purge expired trash entry {}: {:?}", entry.id, e);
+        }
+    }
+}
+
+/// Permanently delete every trash entry
+pub fn purge_all_trash(data_dir: &Path) -> Result<(), TrashError> {
+    match fs::remove_dir_all(trash_path(data_dir)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(TrashError::DeleteEntryError(e)),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use super::*;
+
+    #[test]
+    fn trash_round_trip() {
+        let root = TempDir::new("trash-test");
+        let data_dir = root.join("data");
+        let profile_dir = root.join("profile");
+        fs::create_dir_all(&profile_dir).unwrap();
+        fs::write(profile_dir.join("places.sqlite"), "bookmarks").unwrap();
+
+        let profile = ProfileEntry::new("Work", profile_dir.to_string_lossy().into_owned(), false, None, HashMap::new());
+        let entry = move_to_trash(&data_dir, &profile, &profile_dir, Some(2)).unwrap();
+        assert!(!profile_dir.exists());
+
+        let trash = list_trash(&data_dir);
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].id, entry.id);
+        assert_eq!(trash[0].name, "Work");
+        assert_eq!(trash[0].order_position, Some(2));
+
+        take_from_trash(&data_dir, &entry.id, &profile_dir).unwrap();
+        assert_eq!(fs::read(profile_dir.join("places.sqlite")).unwrap(), b"bookmarks");
+        assert!(list_trash(&data_dir).is_empty());
+        assert!(matches!(read_trash_entry(&data_dir, &entry.id), Err(TrashError::EntryNotFound)));
+    }
+
+    #[test]
+    fn returned_profile_keeps_its_trash_entry() {
+        let root = TempDir::new("trash-test");
+        let data_dir = root.join("data");
+        let profile_dir = root.join("profile");
+        fs::create_dir_all(&profile_dir).unwrap();
+        fs::write(profile_dir.join("places.sqlite"), "bookmarks").unwrap();
+
+        let profile = ProfileEntry::new("Work", profile_dir.to_string_lossy().into_owned(), false, None, HashMap::new());
+        let entry = move_to_trash(&data_dir, &profile, &profile_dir, Some(2)).unwrap();
+        let restored_dir = root.join("restored");
+        let taken = take_from_trash(&data_dir, &entry.id, &restored_dir).unwrap();
+
+        return_to_trash(&data_dir, &taken, &restored_dir).unwrap();
+        assert!(!restored_dir.exists());
+        let trash = list_trash(&data_dir);
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].id, entry.id);
+        assert_eq!(trash[0].deleted_at, entry.deleted_at);
+
+        take_from_trash(&data_dir, &entry.id, &profile_dir).unwrap();
+        assert_eq!(fs::read(profile_dir.join("places.sqlite")).unwrap(), b"bookmarks");
+    }
+}
@@ -1,14 +1,120 @@
-use once_cell::sync::Lazy;
-use semver::{VersionReq};
-use crate::AppState;
+use once_cell::sync::{Lazy, OnceCell};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::native_req::NATIVE_MESSAGE_COMMANDS;
+use crate::native_resp::NATIVE_RESPONSE_EVENTS;
 
-/*
-pub const MIN_VERSION_2: Lazy<VersionReq> = Lazy::new(|| VersionReq::parse(">=2.0.0").unwrap());
+/// Version of the protocol spoken between the connector and the extension, bump this whenever
+/// commands, events or payload shapes change
+pub const PROTOCOL_VERSION: u32 = 2;
 
-pub fn is_min_version_2(app_state: &AppState) -> bool {
-    match &app_state.extension_version {
-        None => false,
-        Some(version) => MIN_VERSION_2.matches(&version)
+/// Protocol features that older extensions may not understand
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolFeature {
+    /// Responses that are too large for a single native message are split into chunks
+    ChunkedResponses,
+}
+
+static MIN_VERSION_2: Lazy<VersionReq> = Lazy::new(|| VersionReq::parse(">=2.0.0").unwrap());
+
+impl ProtocolFeature {
+    /// Extension versions that understand the feature
+    fn version_req(&self) -> &'static VersionReq {
+        match self {
+            ProtocolFeature::ChunkedResponses => &MIN_VERSION_2,
+        }
+    }
+}
+
+/// Features this connector can use
+pub const CONNECTOR_FEATURES: &[ProtocolFeature] = &[ProtocolFeature::ChunkedResponses];
+
+/// What the extension we are talking to understands
+#[derive(Debug)]
+pub struct ExtensionCompat {
+    version: Option<Version>,
+    features: Option<Vec<ProtocolFeature>>
+}
+
+impl ExtensionCompat {
+    /// Features this connector does not know (e.g. from newer extensions) are ignored
+    pub fn new(version: Option<Version>, features: Option<&[String]>) -> ExtensionCompat {
+        ExtensionCompat {
+            version,
+            features: features.map(|features| features.iter()
+                .filter_map(|f| serde_json::from_value(Value::String(f.clone())).ok())
+                .collect())
+        }
+    }
+
+    /// Features announced by the extension take precedence over the ones derived from its
+    /// version. Extensions that announce neither are assumed to support nothing.
+    pub fn supports(&self, feature: ProtocolFeature) -> bool {
+        match &self.features {
+            Some(features) => features.contains(&feature),
+            None => self.version.as_ref().is_some_and(|v| feature.version_req().matches(v))
+        }
+    }
+}
+
+// Set once initialization is complete
+static EXTENSION_COMPAT: OnceCell<ExtensionCompat> = OnceCell::new();
+
+pub fn set_extension_compat(compat: ExtensionCompat) {
+    if EXTENSION_COMPAT.set(compat).is_err() {
+        log::warn!("Extension compatibility was already negotiated, ignoring new version and features.");
+    }
+}
+
+/// Whether the extension understands the specified feature, nothing is supported before
+/// initialization is complete
+pub fn extension_supports(feature: ProtocolFeature) -> bool {
+    EXTENSION_COMPAT.get().is_some_and(|compat| compat.supports(feature))
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConnectorCapabilities {
+    pub protocol_version: u32,
+    pub chunked_responses: bool,
+    pub supported_features: &'static [ProtocolFeature],
+    pub supported_commands: &'static [&'static str],
+    pub supported_events: &'static [&'static str],
+}
+
+impl ConnectorCapabilities {
+    pub fn current() -> ConnectorCapabilities {
+        ConnectorCapabilities {
+            protocol_version: PROTOCOL_VERSION,
+            chunked_responses: extension_supports(ProtocolFeature::ChunkedResponses),
+            supported_features: CONNECTOR_FEATURES,
+            supported_commands: NATIVE_MESSAGE_COMMANDS,
+            supported_events: NATIVE_RESPONSE_EVENTS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compat(version: Option<&str>, features: Option<&[&str]>) -> ExtensionCompat {
+        let features: Option<Vec<String>> = features.map(|f| f.iter().map(|f| f.to_string()).collect());
+        ExtensionCompat::new(version.map(|v| Version::parse(v).unwrap()), features.as_deref())
+    }
+
+    #[test]
+    fn features_follow_the_extension_version() {
+        assert!(!compat(Some("1.4.2"), None).supports(ProtocolFeature::ChunkedResponses));
+        assert!(compat(Some("2.0.0"), None).supports(ProtocolFeature::ChunkedResponses));
+        assert!(compat(Some("2.3.1"), None).supports(ProtocolFeature::ChunkedResponses));
+        assert!(!compat(None, None).supports(ProtocolFeature::ChunkedResponses));
+    }
+
+    #[test]
+    fn announced_features_override_the_version() {
+        assert!(compat(None, Some(&["ChunkedResponses"])).supports(ProtocolFeature::ChunkedResponses));
+        assert!(compat(Some("1.4.2"), Some(&["ChunkedResponses", "FromTheFuture"])).supports(ProtocolFeature::ChunkedResponses));
+        assert!(!compat(Some("2.0.0"), Some(&[])).supports(ProtocolFeature::ChunkedResponses));
     }
 }
-*/
\ No newline at end of file
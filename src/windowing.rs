@@ -61,6 +61,44 @@ impl WindowingHandle {
         })
     }
 
+    pub fn open_archive_save_picker(&self, default_name: &str) -> Option<PathBuf> {
+        let user_dirs = UserDirs::new();
+        let default_name = default_name.to_owned() + ".tar.gz";
+
+        self.exec_on_main_thread(move || {
+            let home = user_dirs.as_ref().map(|d| d.home_dir());
+
+            let mut file_dialog = FileDialog::new()
+                .set_title("Export profile")
+                .set_file_name(&default_name)
+                .add_filter("Profile archive", &["gz"]);
+
+            if let Some(home) = home {
+                file_dialog = file_dialog.set_directory(home)
+            }
+
+            file_dialog.save_file()
+        })
+    }
+
+    pub fn open_archive_picker(&self) -> Option<PathBuf> {
+        let user_dirs = UserDirs::new();
+
+        self.exec_on_main_thread(move || {
+            let home = user_dirs.as_ref().map(|d| d.home_dir());
+
+            let mut file_dialog = FileDialog::new()
+                .set_title("Import profile")
+                .add_filter("Profile archive", &["gz"]);
+
+            if let Some(home) = home {
+                file_dialog = file_dialog.set_directory(home)
+            }
+
+            file_dialog.pick_file()
+        })
+    }
+
     fn exec_on_main_thread<T: FnOnce() -> Z, Z>(&self, task: T) -> Z
         where T: Send + 'static,
               Z: Send + 'static {